@@ -1,6 +1,8 @@
+use std::{collections::HashMap, io::{self, Write}, rc::Rc};
+
 use enum_iterator::Sequence;
 
-use crate::value::*;
+use crate::{gc::*, object::*, scanner::Span, value::*};
 
 macro_rules! code {
     ($code: expr, $x: expr) => {
@@ -9,9 +11,10 @@ macro_rules! code {
 }
 
 #[repr(u8)]
-#[derive(Sequence, Clone)]
+#[derive(Sequence, Clone, Copy)]
 pub enum OpCode {
     Constant,
+    ConstantLong,
     Nil,
     True,
     False,
@@ -24,10 +27,73 @@ pub enum OpCode {
     Divide,
     Not,
     Negate,
+    Print,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Invoke,
+    InvokeLong,
+    SuperInvoke,
+    SuperInvokeLong,
+    Closure,
+    ClosureLong,
+    CloseUpvalue,
+    Pop,
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    GetGlobalLong,
+    DefineGlobal,
+    DefineGlobalLong,
+    SetGlobal,
+    SetGlobalLong,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    GetPropertyLong,
+    SetProperty,
+    SetPropertyLong,
+    GetSuper,
+    GetSuperLong,
     Return,
+    Class,
+    ClassLong,
+    Inherit,
+    Method,
+    MethodLong,
     Unknown,
 }
 
+impl OpCode {
+    /// The variant of this instruction whose constant operand is 24 bits wide.
+    pub fn long(self) -> Option<OpCode> {
+        match self {
+            OpCode::Constant => Some(OpCode::ConstantLong),
+            OpCode::Invoke => Some(OpCode::InvokeLong),
+            OpCode::SuperInvoke => Some(OpCode::SuperInvokeLong),
+            OpCode::Closure => Some(OpCode::ClosureLong),
+            OpCode::GetGlobal => Some(OpCode::GetGlobalLong),
+            OpCode::DefineGlobal => Some(OpCode::DefineGlobalLong),
+            OpCode::SetGlobal => Some(OpCode::SetGlobalLong),
+            OpCode::GetProperty => Some(OpCode::GetPropertyLong),
+            OpCode::SetProperty => Some(OpCode::SetPropertyLong),
+            OpCode::GetSuper => Some(OpCode::GetSuperLong),
+            OpCode::Class => Some(OpCode::ClassLong),
+            OpCode::Method => Some(OpCode::MethodLong),
+            _ => None,
+        }
+    }
+
+    pub fn is_long(&self) -> bool {
+        matches!(self,
+            OpCode::ConstantLong | OpCode::InvokeLong | OpCode::SuperInvokeLong | OpCode::ClosureLong
+            | OpCode::GetGlobalLong | OpCode::DefineGlobalLong | OpCode::SetGlobalLong
+            | OpCode::GetPropertyLong | OpCode::SetPropertyLong | OpCode::GetSuperLong
+            | OpCode::ClassLong | OpCode::MethodLong)
+    }
+}
+
 impl From<OpCode> for u8 {
     fn from(value: OpCode) -> Self {
         value as u8
@@ -37,7 +103,7 @@ impl From<OpCode> for u8 {
 impl From<u8> for OpCode {
     fn from(value: u8) -> Self {
         for i in enum_iterator::all::<Self>() {
-            if code!(i.clone(), value) {
+            if code!(i, value) {
                 return i;
             } 
         }
@@ -46,96 +112,342 @@ impl From<u8> for OpCode {
 }
    
 
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(*const ObjString),
+}
+
+impl ConstantKey {
+    fn of(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(x) => Some(Self::Number(x.to_bits())),
+            Value::String(x) => Some(Self::String(x.as_ptr())),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct LineStart {
+    offset: usize,
+    line: usize,
+}
+
+/// The column part of an instruction's span, keyed by where the instruction
+/// starts. Lines are kept in their own run-length encoded table, and the rest
+/// is narrowed to `u32` so an entry stays at 16 bytes.
+#[derive(Clone, Copy)]
+struct SpanStart {
+    offset: u32,
+    source_offset: u32,
+    column: u32,
+    length: u32,
+}
+
+impl SpanStart {
+    fn new(offset: usize, span: Span) -> Self {
+        let narrow = |x: usize| u32::try_from(x).unwrap_or(u32::MAX);
+        Self { offset: narrow(offset), source_offset: narrow(span.offset), column: narrow(span.column), length: narrow(span.length) }
+    }
+
+    fn same_place(&self, other: &Self) -> bool {
+        (self.source_offset, self.column, self.length) == (other.source_offset, other.column, other.length)
+    }
+}
+
 pub struct Chunk {
     code: Vec<u8>,
-    pub lines: Vec<usize>,
+    lines: Vec<LineStart>,
+    spans: Vec<SpanStart>,
     constants: ValueArray,
+    constant_indices: HashMap<ConstantKey, usize>,
+    pub(crate) source: Rc<str>,
+}
+
+impl Trace for Chunk {
+    fn trace(&self, heap: &mut Heap) {
+        self.constants.trace(heap);
+    }
 }
 
 impl Chunk {
-    pub fn new() -> Self {
+    pub(crate) fn new(source: Rc<str>) -> Self {
         Self { 
             code: Vec::new(),
             constants: ValueArray::new(),
+            constant_indices: HashMap::new(),
             lines: Vec::new(),
+            spans: Vec::new(),
+            source,
         }
     }
 
-    pub fn write<T: Into<u8>>(&mut self, byte: T, line: usize) {
+    pub(crate) fn write<T: Into<u8>>(&mut self, byte: T, span: Span) {
+        let offset = self.code.len();
+        if self.lines.last().is_none_or(|last| last.line != span.line) {
+            self.lines.push(LineStart { offset, line: span.line });
+        }
+
+        let start = SpanStart::new(offset, span);
+        if self.spans.last().is_none_or(|last| !last.same_place(&start)) {
+            self.spans.push(start);
+        }
         self.code.push(byte.into());
-        self.lines.push(line)
     }
 
-    pub fn get(&self, ip: usize) -> u8 {
+    pub fn get_span(&self, offset: usize) -> Span {
+        let run = self.spans.partition_point(|start| start.offset as usize <= offset);
+        let start = self.spans[run - 1];
+        Span {
+            offset: start.source_offset as usize,
+            line: self.get_line(offset),
+            column: start.column as usize,
+            length: start.length as usize,
+        }
+    }
+
+    pub fn get_line(&self, offset: usize) -> usize {
+        let run = self.lines.partition_point(|start| start.offset <= offset);
+        self.lines[run - 1].line
+    }
+
+    pub(crate) fn get(&self, ip: usize) -> u8 {
         self.code[ip]
     }
 
-    pub fn add_constant(&mut self, value: Value) -> usize {
+    pub(crate) fn set(&mut self, offset: usize, byte: u8) {
+        self.code[offset] = byte;
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    pub(crate) fn add_constant(&mut self, value: Value) -> usize {
+        let key = ConstantKey::of(&value);
+        if let Some(seq) = key.as_ref().and_then(|key| self.constant_indices.get(key)) {
+            return *seq;
+        }
+
         self.constants.write(value);
-        self.constants.len() - 1
+        let seq = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_indices.insert(key, seq);
+        }
+        seq
     }
 
-    pub fn get_constant(&self, seq: usize) -> Value {
-        self.constants.get(seq)
+    /// Copies the bytecode into a new chunk, passing every constant through `f`.
+    /// Constants are copied by index, since the bytecode already refers to them that way.
+    pub(crate) fn map_constants(&self, mut f: impl FnMut(Value) -> Value) -> Chunk {
+        let mut chunk = Chunk::new(self.source.clone());
+        chunk.code = self.code.clone();
+        chunk.lines = self.lines.clone();
+        chunk.spans = self.spans.clone();
+        for seq in 0..self.constants.len() {
+            chunk.constants.write(f(self.constants.get(seq)));
+        }
+        chunk
     }
 
-    pub fn print_value(&self, constant: Value) {
-        print!("{}", constant )
-        
+    /// Bytes owned by the chunk outside of its own struct.
+    pub(crate) fn heap_size(&self) -> usize {
+        self.code.capacity()
+            + self.lines.capacity() * std::mem::size_of::<LineStart>()
+            + self.spans.capacity() * std::mem::size_of::<SpanStart>()
+            + self.constants.heap_size()
+            + self.constant_indices.capacity() * std::mem::size_of::<(ConstantKey, usize)>()
+    }
+
+    pub(crate) fn get_constant(&self, seq: usize) -> Value {
+        self.constants.get(seq)
     }
 
-    pub fn disassamble(&self, name: &str) {
-        println!("== {name} ==");
+    pub fn disassamble(&self, name: &str, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "== {name} == ({} constants)", self.constants.len())?;
 
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassamble_instruction(offset);
+            offset = self.disassamble_instruction(offset, out)?;
         }
+        Ok(())
     }
 
-    pub fn disassamble_instruction(&self, offset: usize) -> usize {
-        print!("{offset:04} ");
+    pub(crate) fn disassamble_instruction(&self, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        write!(out, "{offset:04} ")?;
 
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ");
+        let line = self.get_line(offset);
+        if offset > 0 && line == self.get_line(offset - 1) {
+            write!(out, "   | ")?;
         } else {
-            print!("{:4} ", self.lines[offset]);
+            write!(out, "{line:4} ")?;
         }
         
         let instruction = self.code[offset];
-        match instruction.into() {
-            OpCode::Constant => self.constant_instruction("OP_CONSTANT", offset),
-            OpCode::Nil => self.simple_instruction("OP_NIL", offset),
-            OpCode::True => self.simple_instruction("OP_TRUE", offset),
-            OpCode::False => self.simple_instruction("OP_FALSE", offset),
-            OpCode::Equal => self.simple_instruction("OP_EQUAL", offset),
-            OpCode::Greater => self.simple_instruction("OP_GREATER", offset),
-            OpCode::Less => self.simple_instruction("OP_LESS", offset),
-            OpCode::Return => self.simple_instruction("OP_RETURN", offset),
-            OpCode::Negate => self.simple_instruction("OP_NEGATE", offset),
-            OpCode::Not => self.simple_instruction("OP_NOT", offset),
-            OpCode::Add => self.simple_instruction("OP_ADD", offset),
-            OpCode::Subtract => self.simple_instruction("OP_SUBTRACT", offset),
-            OpCode::Multiply => self.simple_instruction("OP_MULTIPLY", offset),
-            OpCode::Divide => self.simple_instruction("OP_DIVIDE", offset),
+        let op: OpCode = instruction.into();
+        let long = op.is_long();
+        match op {
+            OpCode::Constant => self.constant_instruction("OP_CONSTANT", offset, long, out),
+            OpCode::ConstantLong => self.constant_instruction("OP_CONSTANT_LONG", offset, long, out),
+            OpCode::Nil => self.simple_instruction("OP_NIL", offset, out),
+            OpCode::True => self.simple_instruction("OP_TRUE", offset, out),
+            OpCode::False => self.simple_instruction("OP_FALSE", offset, out),
+            OpCode::Equal => self.simple_instruction("OP_EQUAL", offset, out),
+            OpCode::Greater => self.simple_instruction("OP_GREATER", offset, out),
+            OpCode::Less => self.simple_instruction("OP_LESS", offset, out),
+            OpCode::Print => self.simple_instruction("OP_PRINT", offset, out),
+            OpCode::Jump => self.jump_instruction("OP_JUMP", 1, offset, out),
+            OpCode::JumpIfFalse => self.jump_instruction("OP_JUMP_IF_FALSE", 1, offset, out),
+            OpCode::Loop => self.jump_instruction("OP_LOOP", -1, offset, out),
+            OpCode::Call => self.byte_instruction("OP_CALL", offset, out),
+            OpCode::Invoke => self.invoke_instruction("OP_INVOKE", offset, long, out),
+            OpCode::InvokeLong => self.invoke_instruction("OP_INVOKE_LONG", offset, long, out),
+            OpCode::SuperInvoke => self.invoke_instruction("OP_SUPER_INVOKE", offset, long, out),
+            OpCode::SuperInvokeLong => self.invoke_instruction("OP_SUPER_INVOKE_LONG", offset, long, out),
+            OpCode::Closure | OpCode::ClosureLong => self.closure_instruction(offset, long, out),
+            OpCode::CloseUpvalue => self.simple_instruction("OP_CLOSE_UPVALUE", offset, out),
+            OpCode::Pop => self.simple_instruction("OP_POP", offset, out),
+            OpCode::GetLocal => self.byte_instruction("OP_GET_LOCAL", offset, out),
+            OpCode::SetLocal => self.byte_instruction("OP_SET_LOCAL", offset, out),
+            OpCode::GetGlobal => self.constant_instruction("OP_GET_GLOBAL", offset, long, out),
+            OpCode::GetGlobalLong => self.constant_instruction("OP_GET_GLOBAL_LONG", offset, long, out),
+            OpCode::DefineGlobal => self.constant_instruction("OP_DEFINE_GLOBAL", offset, long, out),
+            OpCode::DefineGlobalLong => self.constant_instruction("OP_DEFINE_GLOBAL_LONG", offset, long, out),
+            OpCode::SetGlobal => self.constant_instruction("OP_SET_GLOBAL", offset, long, out),
+            OpCode::SetGlobalLong => self.constant_instruction("OP_SET_GLOBAL_LONG", offset, long, out),
+            OpCode::GetUpvalue => self.byte_instruction("OP_GET_UPVALUE", offset, out),
+            OpCode::SetUpvalue => self.byte_instruction("OP_SET_UPVALUE", offset, out),
+            OpCode::GetProperty => self.constant_instruction("OP_GET_PROPERTY", offset, long, out),
+            OpCode::GetPropertyLong => self.constant_instruction("OP_GET_PROPERTY_LONG", offset, long, out),
+            OpCode::SetProperty => self.constant_instruction("OP_SET_PROPERTY", offset, long, out),
+            OpCode::SetPropertyLong => self.constant_instruction("OP_SET_PROPERTY_LONG", offset, long, out),
+            OpCode::GetSuper => self.constant_instruction("OP_GET_SUPER", offset, long, out),
+            OpCode::GetSuperLong => self.constant_instruction("OP_GET_SUPER_LONG", offset, long, out),
+            OpCode::Return => self.simple_instruction("OP_RETURN", offset, out),
+            OpCode::Class => self.constant_instruction("OP_CLASS", offset, long, out),
+            OpCode::ClassLong => self.constant_instruction("OP_CLASS_LONG", offset, long, out),
+            OpCode::Inherit => self.simple_instruction("OP_INHERIT", offset, out),
+            OpCode::Method => self.constant_instruction("OP_METHOD", offset, long, out),
+            OpCode::MethodLong => self.constant_instruction("OP_METHOD_LONG", offset, long, out),
+            OpCode::Negate => self.simple_instruction("OP_NEGATE", offset, out),
+            OpCode::Not => self.simple_instruction("OP_NOT", offset, out),
+            OpCode::Add => self.simple_instruction("OP_ADD", offset, out),
+            OpCode::Subtract => self.simple_instruction("OP_SUBTRACT", offset, out),
+            OpCode::Multiply => self.simple_instruction("OP_MULTIPLY", offset, out),
+            OpCode::Divide => self.simple_instruction("OP_DIVIDE", offset, out),
             _ => {
-                println!("Unknown opcode {instruction}");
-                offset + 1
+                writeln!(out, "Unknown opcode {instruction}")?;
+                Ok(offset + 1)
             },
         }
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let seq = self.code[offset + 1];
-        let constant = self.constants.get(seq as usize);
-        print!("{name:-16} {seq:4} '");
-        self.print_value(constant);
-        println!("'");
-        offset + 2
+    fn operand(&self, offset: usize, long: bool) -> (usize, usize) {
+        if long {
+            let seq = u32::from_be_bytes([0, self.code[offset], self.code[offset + 1], self.code[offset + 2]]);
+            (seq as usize, offset + 3)
+        } else {
+            (self.code[offset] as usize, offset + 1)
+        }
+    }
+
+    fn constant_instruction(&self, name: &str, offset: usize, long: bool, out: &mut dyn Write) -> io::Result<usize> {
+        let (seq, offset) = self.operand(offset + 1, long);
+        let constant = self.constants.get(seq);
+        writeln!(out, "{name:-16} {seq:4} '{constant}'")?;
+        Ok(offset)
+    }
+
+    fn invoke_instruction(&self, name: &str, offset: usize, long: bool, out: &mut dyn Write) -> io::Result<usize> {
+        let (seq, offset) = self.operand(offset + 1, long);
+        let arg_count = self.code[offset];
+        let constant = self.constants.get(seq);
+        writeln!(out, "{name:-16} ({arg_count} args) {seq:4} '{constant}'")?;
+        Ok(offset + 1)
+    }
+
+    fn closure_instruction(&self, offset: usize, long: bool, out: &mut dyn Write) -> io::Result<usize> {
+        let name = if long { "OP_CLOSURE_LONG" } else { "OP_CLOSURE" };
+        let (seq, mut offset) = self.operand(offset + 1, long);
+        let constant = self.constants.get(seq);
+        writeln!(out, "{name:-16} {seq:4} {constant}")?;
+
+        if let Value::Function(function) = constant {
+            for _ in 0..function.upvalue_count {
+                let is_local = self.code[offset];
+                let index = self.code[offset + 1];
+                let kind = if is_local == 1 { "local" } else { "upvalue" };
+                writeln!(out, "{offset:04}    |                     {kind} {index}")?;
+                offset += 2;
+            }
+        }
+
+        Ok(offset)
+    }
+
+    fn byte_instruction(&self, name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        let slot = self.code[offset + 1];
+        writeln!(out, "{name:-16} {slot:4}")?;
+        Ok(offset + 2)
+    }
+
+    fn jump_instruction(&self, name: &str, sign: isize, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        let jump = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]) as isize;
+        let target = offset as isize + 3 + sign * jump;
+        writeln!(out, "{name:-16} {offset:4} -> {target}")?;
+        Ok(offset + 3)
+    }
+
+    fn simple_instruction(&self, name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        writeln!(out, "{name}")?;
+        Ok(offset + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(line: usize, column: usize) -> Span {
+        Span { offset: 0, line, column, length: 1 }
     }
 
-    fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{name}");
-        offset + 1
+    #[test]
+    fn lines_and_spans_are_run_length_encoded() {
+        let mut chunk = Chunk::new(Rc::from(""));
+        chunk.write(OpCode::Nil, span(1, 1));
+        chunk.write(OpCode::Pop, span(1, 3));
+        chunk.write(OpCode::Nil, span(2, 5));
+        chunk.write(OpCode::Return, span(2, 5));
+        chunk.write(OpCode::Return, span(1, 1));
+
+        assert_eq!(chunk.lines.len(), 3);
+        assert_eq!(chunk.spans.len(), 4);
+        assert_eq!(std::mem::size_of::<SpanStart>(), 16);
+        assert_eq!(chunk.get_span(1), span(1, 3));
+        assert_eq!(chunk.get_span(3), span(2, 5));
+        assert_eq!(chunk.get_span(4), span(1, 1));
+        assert_eq!(chunk.get_line(3), 2);
+    }
+
+    #[test]
+    fn constants_are_deduplicated() {
+        let mut heap = Heap::new();
+        let a = heap.alloc(ObjString::new("a".to_string(), 0));
+        let b = heap.alloc(ObjString::new("b".to_string(), 0));
+        let mut chunk = Chunk::new(Rc::from(""));
+
+        assert_eq!(chunk.add_constant(Value::Number(1.5)), 0);
+        assert_eq!(chunk.add_constant(Value::String(a)), 1);
+        assert_eq!(chunk.add_constant(Value::Number(1.5)), 0);
+        assert_eq!(chunk.add_constant(Value::String(b)), 2);
+        assert_eq!(chunk.add_constant(Value::String(a)), 1);
+        assert_eq!(chunk.add_constant(Value::Nil), 3);
+        assert_eq!(chunk.add_constant(Value::Nil), 4);
     }
 }
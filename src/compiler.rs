@@ -1,6 +1,8 @@
+use std::rc::Rc;
+
 use enum_iterator::Sequence;
 
-use crate::{scanner::*, vm::*, chunk::*, value::*};
+use crate::{scanner::*, chunk::*, value::*, object::*, gc::*, diagnostics::*};
 
 #[derive(Default)]
 pub struct Parser {
@@ -26,45 +28,122 @@ pub enum Prec {
 }
 
 pub struct ParseRule<'a> {
-    prefix: Option<fn(&mut Compiler<'a>)>,
-    infix: Option<fn(&mut Compiler<'a>)>,
+    prefix: Option<fn(&mut Compiler<'a>, bool)>,
+    infix: Option<fn(&mut Compiler<'a>, bool)>,
     precedence: Prec,
 }
 
 impl<'a> ParseRule<'a> {
-    pub fn new(prefix: Option<fn(&mut Compiler<'a>)>, infix: Option<fn(&mut Compiler<'a>)>, precedence: Prec) -> Self {
+    pub fn new(prefix: Option<fn(&mut Compiler<'a>, bool)>, infix: Option<fn(&mut Compiler<'a>, bool)>, precedence: Prec) -> Self {
         Self {prefix, infix, precedence}
     }
 }
 
+pub struct Local {
+    name: Token,
+    depth: Option<usize>,
+    is_captured: bool,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub struct Upvalue {
+    index: u8,
+    is_local: bool,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+pub struct FunctionState {
+    function: ObjFunction,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    pub fn new(function_type: FunctionType, name: Option<Gc<ObjString>>, source: Rc<str>) -> Self {
+        // Slot zero holds the function being called, or the receiver for methods.
+        let mut callee = Local { name: Token::default(), depth: Some(0), is_captured: false };
+        if function_type != FunctionType::Function {
+            callee.name.lexme = "this".to_string();
+        }
+        Self {
+            function: ObjFunction::new(name, source),
+            function_type,
+            locals: vec![callee],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+}
+
+pub struct ClassState {
+    has_superclass: bool,
+}
+
+impl Trace for FunctionState {
+    fn trace(&self, heap: &mut Heap) {
+        self.function.trace(heap);
+    }
+}
+
+/// Where the compiler puts the objects it creates: a running VM, or the private
+/// heap of a `CompiledScript`.
+pub trait Allocator {
+    /// Allocates a finished function. `roots` holds the functions still being compiled.
+    fn alloc_function(&mut self, function: ObjFunction, roots: &dyn Trace) -> Gc<ObjFunction>;
+    fn intern(&mut self, chars: String, roots: &dyn Trace) -> Gc<ObjString>;
+    /// Receives the `debug_print_code` output.
+    #[cfg(feature = "debug_print_code")]
+    fn trace_sink(&mut self) -> &mut dyn std::io::Write;
+}
+
 pub struct Compiler<'a> {
+    heap: &'a mut dyn Allocator,
     parser: Parser,
     scanner: Scanner,
-    chunk: &'a mut Chunk,
+    source: Rc<str>,
+    diagnostics: Vec<Diagnostic>,
+    states: Vec<FunctionState>,
+    classes: Vec<ClassState>,
 }
 
 impl<'a> Compiler<'a> {
-    pub fn new(source: &str, chunk: &'a mut Chunk) -> Self {
+    pub fn new(source: &str, heap: &'a mut dyn Allocator) -> Self {
+        let source: Rc<str> = Rc::from(source);
         Self {
+            heap,
             parser: Parser::default(),
-            scanner: Scanner::new(source), 
-            chunk,
+            scanner: Scanner::new(&source), 
+            states: vec![FunctionState::new(FunctionType::Script, None, source.clone())],
+            source,
+            diagnostics: Vec::new(),
+            classes: Vec::new(),
         }
     }
 
-    pub fn compile(&mut self) -> InterpretResult<()> {
+    pub fn compile(&mut self) -> Result<Gc<ObjFunction>, Diagnostics> {
         self.parser.had_error = false;
 
         self.advance();
-        self.expression();
-        self.consume(TokenType::Eof, "Expect end of expression.");
-        
-        self.end_compiler();
+
+        while !self.is_match(TokenType::Eof) {
+            self.declaration();
+        }
+
+        let function = self.end_compiler();
 
         if self.parser.had_error {
-            Err(InterpretError::CompilerError)
+            Err(Diagnostics(std::mem::take(&mut self.diagnostics)))
         } else {
-            Ok(())
+            Ok(self.alloc_function(function))
         }
     }    
     
@@ -81,31 +160,24 @@ impl<'a> Compiler<'a> {
     }
 
     fn error_at_current(&mut self, message: &str) {
-        self.error_at(&self.parser.current, message);
+        let token = self.parser.current.clone();
+        self.error_at(&token, message);
         self.parser.had_error = true;
         self.parser.panic_mode = true;
     }
 
     fn error(&mut self, message: &str) {
-        self.error_at(&self.parser.previous, message);
+        let token = self.parser.previous.clone();
+        self.error_at(&token, message);
         self.parser.had_error = true;
         self.parser.panic_mode = true;
     }
 
-    fn error_at(&self, token: &Token, message: &str) {
+    fn error_at(&mut self, token: &Token, message: &str) {
         if self.parser.panic_mode {return ;}
 
-        eprint!("[line {}] Error", token.line);
-
-        if token.t == TokenType::Eof {
-            eprint!(" at end");
-        } else if token.t == TokenType::Error {
-            // ignore
-        } else {
-            eprint!(" at '{}'", token.lexme);
-        }
-
-        eprintln!(":{message}");
+        let code = if token.t == TokenType::Error { SYNTAX_ERROR } else { COMPILE_ERROR };
+        self.diagnostics.push(Diagnostic::new(code, message, token.span(), &self.source));
     }
 
     fn consume(&mut self, t: TokenType, message: &str) {
@@ -117,8 +189,30 @@ impl<'a> Compiler<'a> {
         self.error_at_current(message);
     }
 
+    fn check(&self, t: TokenType) -> bool {
+        self.parser.current.t == t
+    }
+
+    fn is_match(&mut self, t: TokenType) -> bool {
+        if !self.check(t) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
     fn emit_byte(&mut self, byte: u8) {
-        self.chunk.write(byte, self.parser.previous.line);
+        let span = self.parser.previous.span();
+        self.emit_byte_at(byte, span);
+    }
+
+    fn emit_byte_at(&mut self, byte: u8, span: Span) {
+        self.current_chunk().write(byte, span);
+    }
+
+    fn emit_bytes_at(&mut self, byte1: u8, byte2: u8, span: Span) {
+        self.emit_byte_at(byte1, span);
+        self.emit_byte_at(byte2, span);
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -126,41 +220,124 @@ impl<'a> Compiler<'a> {
         self.emit_byte(byte2);
     }
 
+    fn emit_operand(&mut self, op: OpCode, operand: usize) {
+        let span = self.parser.previous.span();
+        self.emit_operand_at(op, operand, span);
+    }
+
+    /// Emits `op` with a one-byte operand, or its long form when the operand needs 24 bits.
+    fn emit_operand_at(&mut self, op: OpCode, operand: usize, span: Span) {
+        match op.long() {
+            Some(long) if operand > u8::MAX as usize => {
+                let [_, high, middle, low] = (operand as u32).to_be_bytes();
+                self.emit_bytes_at(long as u8, high, span);
+                self.emit_bytes_at(middle, low, span);
+            }
+            _ => self.emit_bytes_at(op as u8, operand as u8, span),
+        }
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop as u8);
+
+        let offset = self.current_chunk().len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+
+        let [high, low] = (offset as u16).to_be_bytes();
+        self.emit_bytes(high, low);
+    }
+
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction as u8);
+        self.emit_bytes(0xff, 0xff);
+        self.current_chunk().len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        let jump = self.current_chunk().len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+
+        let [high, low] = (jump as u16).to_be_bytes();
+        self.current_chunk().set(offset, high);
+        self.current_chunk().set(offset + 1, low);
+    }
+
+    fn alloc_function(&mut self, function: ObjFunction) -> Gc<ObjFunction> {
+        self.heap.alloc_function(function, &self.states)
+    }
+
+    fn alloc_string(&mut self, chars: String) -> Gc<ObjString> {
+        self.heap.intern(chars, &self.states)
+    }
+
+    fn state(&mut self) -> &mut FunctionState {
+        self.states.last_mut().unwrap()
+    }
+
     fn current_chunk(&mut self) -> &mut Chunk {
-        self.chunk
+        &mut self.state().function.chunk
     }
 
-    fn end_compiler(&mut self) {
+    fn end_compiler(&mut self) -> ObjFunction {
         self.emit_return();
+        let state = self.states.pop().unwrap();
 
         #[cfg(feature = "debug_print_code")] {
             if !self.parser.had_error {
-                self.current_chunk().disassamble("code");
+                let _ = state.function.chunk.disassamble(&state.function.to_string(), self.heap.trace_sink());
             }
         }
+
+        state.function
     }
 
-    fn binary(&mut self) {
+    fn binary(&mut self, _can_assign: bool) {
         let operator_type = self.parser.previous.t;
+        let operator = self.parser.previous.span();
         let rule = get_rule(operator_type);
         self.parse_precedence(rule.precedence.next().unwrap());
 
         match operator_type {
-            TokenType::BangEqual => self.emit_bytes(OpCode::Equal as u8, OpCode::Not as u8),
-            TokenType::Equal => self.emit_byte(OpCode::Equal as u8),
-            TokenType::Greater => self.emit_byte(OpCode::Greater as u8),
-            TokenType::GreaterEqual => self.emit_bytes(OpCode::Less as u8, OpCode::Not as u8),
-            TokenType::Less => self.emit_byte(OpCode::Less as u8),
-            TokenType::LessEqual => self.emit_bytes(OpCode::Greater as u8, OpCode::Not as u8),
-            TokenType::Plus => self.emit_byte(OpCode::Add as u8),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract as u8),
-            TokenType::Star => self.emit_byte(OpCode::Multiply as u8),
-            TokenType::Slash => self.emit_byte(OpCode::Divide as u8),
-            _ => return,
+            TokenType::BangEqual => self.emit_bytes_at(OpCode::Equal as u8, OpCode::Not as u8, operator),
+            TokenType::Equal => self.emit_byte_at(OpCode::Equal as u8, operator),
+            TokenType::Greater => self.emit_byte_at(OpCode::Greater as u8, operator),
+            TokenType::GreaterEqual => self.emit_bytes_at(OpCode::Less as u8, OpCode::Not as u8, operator),
+            TokenType::Less => self.emit_byte_at(OpCode::Less as u8, operator),
+            TokenType::LessEqual => self.emit_bytes_at(OpCode::Greater as u8, OpCode::Not as u8, operator),
+            TokenType::Plus => self.emit_byte_at(OpCode::Add as u8, operator),
+            TokenType::Minus => self.emit_byte_at(OpCode::Subtract as u8, operator),
+            TokenType::Star => self.emit_byte_at(OpCode::Multiply as u8, operator),
+            TokenType::Slash => self.emit_byte_at(OpCode::Divide as u8, operator),
+            _ => (),
         }
     }
 
-    fn literal(&mut self) {
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_byte(OpCode::Pop as u8);
+        self.parse_precedence(Prec::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop as u8);
+
+        self.parse_precedence(Prec::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
         match self.parser.previous.t {
             TokenType::False => self.emit_byte(OpCode::False as u8),
             TokenType::True => self.emit_byte(OpCode::True as u8),
@@ -169,15 +346,16 @@ impl<'a> Compiler<'a> {
         }
     }
 
-    fn unary(&mut self) {
+    fn unary(&mut self, _can_assign: bool) {
         let operator_type = self.parser.previous.t;
+        let operator = self.parser.previous.span();
 
         self.parse_precedence(Prec::Unary);
 
         match operator_type {
-            TokenType::Bang => self.emit_byte(OpCode::Not as u8),
-            TokenType::Minus => self.emit_byte(OpCode::Negate as u8),
-            _ => return,
+            TokenType::Bang => self.emit_byte_at(OpCode::Not as u8, operator),
+            TokenType::Minus => self.emit_byte_at(OpCode::Negate as u8, operator),
+            _ => (),
         }
     }
 
@@ -186,61 +364,605 @@ impl<'a> Compiler<'a> {
         let prefix_rule = get_rule(self.parser.previous.t).prefix;
         match prefix_rule {
             Some(prefix_rule) => {
-                prefix_rule(self);
+                let can_assign = precedence <= Prec::Assignment;
+                prefix_rule(self, can_assign);
                 while precedence <= get_rule(self.parser.current.t).precedence {
                     self.advance();
                     if let Some(infix_rule) = get_rule(self.parser.previous.t).infix {
-                        infix_rule(self);
+                        infix_rule(self, can_assign);
                     }
                 }
+
+                if can_assign && self.is_match(TokenType::Assign) {
+                    self.error("Invalid assignment target.");
+                }
             }
             _ => self.error("Exprect expression."),
         }
     }
 
-    fn grouping(&mut self) {
+    fn grouping(&mut self, _can_assign: bool) {
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after expression.");
     }
 
-    fn number(&mut self) {
+    fn number(&mut self, _can_assign: bool) {
         let value: f64 = self.parser.previous.lexme.parse().unwrap();
         self.emit_constant(number_val!(value));
     }
 
+    fn string(&mut self, _can_assign: bool) {
+        let lexme = &self.parser.previous.lexme;
+        let value = lexme[1..lexme.len() - 1].to_string();
+        let string = self.alloc_string(value);
+        self.emit_constant(Value::String(string));
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.parser.previous.clone();
+        self.named_variable(&name, can_assign);
+    }
+
+    fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        let span = name.span();
+        let depth = self.states.len() - 1;
+        let (get_op, set_op, arg) = if let Some(arg) = self.resolve_local(depth, name) {
+            (OpCode::GetLocal, OpCode::SetLocal, arg as usize)
+        } else if let Some(arg) = self.resolve_upvalue(depth, name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, arg as usize)
+        } else {
+            (OpCode::GetGlobal, OpCode::SetGlobal, self.identifier_constant(name))
+        };
+
+        if can_assign && self.is_match(TokenType::Assign) {
+            self.expression();
+            self.emit_operand_at(set_op, arg, span);
+        } else {
+            self.emit_operand_at(get_op, arg, span);
+        }
+    }
+
+    fn resolve_local(&mut self, depth: usize, name: &Token) -> Option<u8> {
+        let (slot, local) = self.states[depth].locals.iter().enumerate().rev()
+            .find(|(_, local)| local.name.lexme == name.lexme)?;
+
+        if local.depth.is_none() {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        Some(slot as u8)
+    }
+
+    fn resolve_upvalue(&mut self, depth: usize, name: &Token) -> Option<u8> {
+        if depth == 0 {
+            return None;
+        }
+
+        if let Some(local) = self.resolve_local(depth - 1, name) {
+            self.states[depth - 1].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(depth, local, true));
+        }
+
+        let upvalue = self.resolve_upvalue(depth - 1, name)?;
+        Some(self.add_upvalue(depth, upvalue, false))
+    }
+
+    fn add_upvalue(&mut self, depth: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &mut self.states[depth].upvalues;
+
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+
+        if upvalues.len() > u8::MAX as usize {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        upvalues.push(upvalue);
+        (upvalues.len() - 1) as u8
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let paren = self.parser.previous.span();
+        let arg_count = self.argument_list();
+        self.emit_bytes_at(OpCode::Call as u8, arg_count, paren);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.is_match(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count as u8
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let property = self.parser.previous.clone();
+        let name = self.identifier_constant(&property);
+
+        let span = property.span();
+
+        if can_assign && self.is_match(TokenType::Assign) {
+            self.expression();
+            self.emit_operand_at(OpCode::SetProperty, name, span);
+        } else if self.is_match(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.emit_operand_at(OpCode::Invoke, name, span);
+            self.emit_byte_at(arg_count, span);
+        } else {
+            self.emit_operand_at(OpCode::GetProperty, name, span);
+        }
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => self.error("Can't use 'super' in a class with no superclass."),
+            _ => (),
+        }
+
+        let keyword = self.parser.previous.span();
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let method = self.parser.previous.clone();
+        let name = self.identifier_constant(&method);
+
+        let span = method.span();
+
+        self.named_variable(&synthetic_token("this", keyword), false);
+        if self.is_match(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(&synthetic_token("super", keyword), false);
+            self.emit_operand_at(OpCode::SuperInvoke, name, span);
+            self.emit_byte_at(arg_count, span);
+        } else {
+            self.named_variable(&synthetic_token("super", keyword), false);
+            self.emit_operand_at(OpCode::GetSuper, name, span);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        self.variable(false);
+    }
+
     fn emit_return(&mut self) {
+        if self.state().function_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::GetLocal as u8, 0);
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
         self.emit_byte(OpCode::Return as u8);
     }
 
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::Constant as u8, constant);
+        self.emit_operand(OpCode::Constant, constant);
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
+    fn make_constant(&mut self, value: Value) -> usize {
         let constant = self.current_chunk().add_constant(value);
-        if constant > u8::MAX as usize {
+        if constant >= 1 << 24 {
             self.error("Too many constants in one chunk.");
             return 0;
         }
 
-        constant as u8
+        constant
     }    
 
     fn expression(&mut self) {
         self.parse_precedence(Prec::Assignment);
     }
 
+    fn declaration(&mut self) {
+        if self.is_match(TokenType::Class) {
+            self.class_declaration();
+        } else if self.is_match(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.is_match(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.parser.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn synchronize(&mut self) {
+        self.parser.panic_mode = false;
+
+        while self.parser.current.t != TokenType::Eof {
+            if self.parser.previous.t == TokenType::SemiColon {
+                return;
+            }
+
+            match self.parser.current.t {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => (),
+            }
+
+            self.advance();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.parser.previous.clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_operand(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState { has_superclass: false });
+
+        if self.is_match(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            if class_name.lexme == self.parser.previous.lexme {
+                self.error("A class can't inherit from itself.");
+            }
+
+            self.begin_scope();
+            let superclass = self.parser.previous.span();
+            self.add_local(synthetic_token("super", superclass));
+            self.define_variable(0);
+
+            self.named_variable(&class_name, false);
+            self.emit_byte(OpCode::Inherit as u8);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        self.named_variable(&class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop as u8);
+
+        if self.classes.pop().is_some_and(|class| class.has_superclass) {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let method_name = self.parser.previous.clone();
+        let constant = self.identifier_constant(&method_name);
+
+        let function_type = if method_name.lexme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_operand(OpCode::Method, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self.alloc_string(self.parser.previous.lexme.clone());
+        self.states.push(FunctionState::new(function_type, Some(name), self.source.clone()));
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.state().function.arity += 1;
+                if self.state().function.arity > u8::MAX as usize {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.is_match(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        let upvalues = std::mem::take(&mut self.state().upvalues);
+        let mut function = self.end_compiler();
+        function.upvalue_count = upvalues.len();
+
+        let function = self.alloc_function(function);
+        let constant = self.make_constant(Value::Function(function));
+        self.emit_operand(OpCode::Closure, constant);
+
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.is_match(TokenType::Assign) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+        self.consume(TokenType::SemiColon, "Expect ';' after variable declaration.");
+
+        self.define_variable(global);
+    }
+
+    fn parse_variable(&mut self, message: &str) -> usize {
+        self.consume(TokenType::Identifier, message);
+
+        self.declare_variable();
+        if self.state().scope_depth > 0 {
+            return 0;
+        }
+
+        let name = self.parser.previous.clone();
+        self.identifier_constant(&name)
+    }
+
+    fn declare_variable(&mut self) {
+        let scope_depth = self.state().scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+
+        let name = self.parser.previous.clone();
+        let duplicate = self.state().locals.iter().rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name.lexme == name.lexme);
+        if duplicate {
+            self.error("Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token) {
+        if self.state().locals.len() > u8::MAX as usize {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.state().locals.push(Local { name, depth: None, is_captured: false });
+    }
+
+    fn mark_initialized(&mut self) {
+        let scope_depth = self.state().scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = self.state().locals.last_mut() {
+            local.depth = Some(scope_depth);
+        }
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> usize {
+        let string = self.alloc_string(name.lexme.clone());
+        self.make_constant(Value::String(string))
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_operand(OpCode::DefineGlobal, global);
+    }
+
+    fn statement(&mut self) {
+        if self.is_match(TokenType::Print) {
+            self.print_statement();
+        } else if self.is_match(TokenType::Return) {
+            self.return_statement();
+        } else if self.is_match(TokenType::For) {
+            self.for_statement();
+        } else if self.is_match(TokenType::If) {
+            self.if_statement();
+        } else if self.is_match(TokenType::While) {
+            self.while_statement();
+        } else if self.is_match(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state().scope_depth -= 1;
+
+        let scope_depth = self.state().scope_depth;
+        while let Some(local) = self.state().locals.last() {
+            if local.depth.is_some_and(|depth| depth <= scope_depth) {
+                break;
+            }
+            if local.is_captured {
+                self.emit_byte(OpCode::CloseUpvalue as u8);
+            } else {
+                self.emit_byte(OpCode::Pop as u8);
+            }
+            self.state().locals.pop();
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop as u8);
+
+        if self.is_match(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop as u8);
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.is_match(TokenType::SemiColon) {
+            // No initializer.
+        } else if self.is_match(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().len();
+        let mut exit_jump = None;
+        if !self.is_match(TokenType::SemiColon) {
+            self.expression();
+            self.consume(TokenType::SemiColon, "Expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop as u8);
+        }
+
+        if !self.is_match(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().len();
+            self.expression();
+            self.emit_byte(OpCode::Pop as u8);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop as u8);
+        }
+
+        self.end_scope();
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::SemiColon, "Expect ';' after value.");
+        self.emit_byte(OpCode::Print as u8);
+    }
+
+    fn return_statement(&mut self) {
+        if self.state().function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.is_match(TokenType::SemiColon) {
+            self.emit_return();
+        } else {
+            if self.state().function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::SemiColon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::Return as u8);
+        }
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::SemiColon, "Expect ';' after expression.");
+        self.emit_byte(OpCode::Pop as u8);
+    }
+
+}
+
+/// Builds an identifier the source never spelled out, placed at `span` so the
+/// code emitted for it is attributed to a real line.
+fn synthetic_token(text: &str, span: Span) -> Token {
+    let Span { offset, line, column, length } = span;
+    Token { t: TokenType::Identifier, lexme: text.to_string(), line, offset, column, length }
 }
 
 fn get_rule<'a>(t: TokenType) -> ParseRule<'a> {
         match t {
-            TokenType::LeftParen    => ParseRule::new(Some(Compiler::grouping), None, Prec::None),
+            TokenType::LeftParen    => ParseRule::new(Some(Compiler::grouping), Some(Compiler::call), Prec::Call),
             TokenType::RightParen   => ParseRule::new(None, None, Prec::None),
             TokenType::LeftBrace    => ParseRule::new(None, None, Prec::None),
             TokenType::RightBrace   => ParseRule::new(None, None, Prec::None),
             TokenType::Comma        => ParseRule::new(None, None, Prec::None), 
-            TokenType::Dot          => ParseRule::new(None, None, Prec::None),
+            TokenType::Dot          => ParseRule::new(None, Some(Compiler::dot), Prec::Call),
             TokenType::Minus        => ParseRule::new(Some(Compiler::unary), Some(Compiler::binary), Prec::Term),
             TokenType::Plus         => ParseRule::new(None, Some(Compiler::binary), Prec::Term),
             TokenType::SemiColon    => ParseRule::new(None, None, Prec::None), 
@@ -254,10 +976,10 @@ fn get_rule<'a>(t: TokenType) -> ParseRule<'a> {
             TokenType::GreaterEqual => ParseRule::new(None, Some(Compiler::binary), Prec::Comparison),
             TokenType::Less         => ParseRule::new(None, Some(Compiler::binary), Prec::Comparison),
             TokenType::LessEqual    => ParseRule::new(None, Some(Compiler::binary), Prec::Comparison),
-            TokenType::Identifier   => ParseRule::new(None, None, Prec::None),
-            TokenType::String       => ParseRule::new(None, None, Prec::None),
+            TokenType::Identifier   => ParseRule::new(Some(Compiler::variable), None, Prec::None),
+            TokenType::String       => ParseRule::new(Some(Compiler::string), None, Prec::None),
             TokenType::Number       => ParseRule::new(Some(Compiler::number), None, Prec::None),
-            TokenType::And          => ParseRule::new(None, None, Prec::None),
+            TokenType::And          => ParseRule::new(None, Some(Compiler::and), Prec::And),
             TokenType::Class        => ParseRule::new(None, None, Prec::None),
             TokenType::Else         => ParseRule::new(None, None, Prec::None),
             TokenType::False        => ParseRule::new(Some(Compiler::literal), None, Prec::None),
@@ -265,11 +987,11 @@ fn get_rule<'a>(t: TokenType) -> ParseRule<'a> {
             TokenType::For          => ParseRule::new(None, None, Prec::None),
             TokenType::If           => ParseRule::new(None, None, Prec::None),
             TokenType::Nil          => ParseRule::new(Some(Compiler::literal), None, Prec::None),
-            TokenType::Or           => ParseRule::new(None, None, Prec::None),
+            TokenType::Or           => ParseRule::new(None, Some(Compiler::or), Prec::Or),
             TokenType::Print        => ParseRule::new(None, None, Prec::None),
             TokenType::Return       => ParseRule::new(None, None, Prec::None),
-            TokenType::Super        => ParseRule::new(None, None, Prec::None),
-            TokenType::This         => ParseRule::new(None, None, Prec::None),
+            TokenType::Super        => ParseRule::new(Some(Compiler::super_), None, Prec::None),
+            TokenType::This         => ParseRule::new(Some(Compiler::this), None, Prec::None),
             TokenType::True         => ParseRule::new(Some(Compiler::literal), None, Prec::None),
             TokenType::Var          => ParseRule::new(None, None, Prec::None),
             TokenType::While        => ParseRule::new(None, None, Prec::None),
@@ -278,3 +1000,23 @@ fn get_rule<'a>(t: TokenType) -> ParseRule<'a> {
         }
     }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    #[test]
+    fn super_accesses_are_attributed_to_the_super_keyword() {
+        let mut vm = VM::new();
+        let source = "class A { m() {} }\nclass B < A {\n  m() {\n    super.m();\n  }\n}\n";
+        let script = Compiler::new(source, &mut vm).compile().unwrap();
+
+        let Value::Function(method) = script.chunk.get_constant(4) else {
+            panic!("expected B.m at constant 4");
+        };
+        // `this` is loaded at offset 0 and `super` at offset 2.
+        assert_eq!(method.chunk.get_line(0), 4);
+        assert_eq!(method.chunk.get_line(2), 4);
+    }
+}
@@ -0,0 +1,103 @@
+use std::{fmt::{Display, Write}, ops::Deref};
+
+use crate::scanner::Span;
+
+pub const SYNTAX_ERROR: &str = "E0001";
+pub const COMPILE_ERROR: &str = "E0002";
+pub const RUNTIME_ERROR: &str = "E0003";
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub message: String,
+    pub span: Span,
+    pub lexeme: String,
+    pub help: Option<String>,
+    source_line: String,
+}
+
+impl Diagnostic {
+    /// Captures the text under `span` and the line it sits on, so the diagnostic
+    /// can be rendered after the source itself is gone.
+    pub fn new(code: &'static str, message: &str, span: Span, source: &str) -> Self {
+        let lexeme = source.get(span.offset..).unwrap_or("").chars().take(span.length).collect();
+        let source_line = source.lines().nth(span.line.saturating_sub(1)).unwrap_or("").to_string();
+        Self { code, message: message.to_string(), span, lexeme, help: None, source_line }
+    }
+
+    pub fn with_help(mut self, help: &str) -> Self {
+        self.help = Some(help.to_string());
+        self
+    }
+
+    /// Renders the diagnostic with the offending source line and a caret underline.
+    pub fn render(&self, color: bool) -> String {
+        let line_text = &self.source_line;
+        let number = self.span.line.to_string();
+        let gutter = " ".repeat(number.len());
+
+        // Keep tabs in the padding so the caret lines up with the source text.
+        let column = self.span.column.max(1);
+        let padding: String = line_text.chars().chain(std::iter::repeat(' '))
+            .take(column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let remaining = line_text.chars().count().saturating_sub(column - 1);
+        let width = self.span.length.min(remaining).max(1);
+
+        let mut out = String::new();
+        let _ = writeln!(out, "{}: {}", paint(&format!("error[{}]", self.code), "1;31", color), paint(&self.message, "1", color));
+        let location = match self.lexeme.lines().next() {
+            Some(text) => format!("at '{text}'"),
+            None => "at end".to_string(),
+        };
+        let _ = writeln!(out, "{gutter}{} line {}, column {column}, {location}", paint("-->", "1;34", color), self.span.line);
+        let _ = writeln!(out, "{gutter} {}", paint("|", "1;34", color));
+        let _ = writeln!(out, "{} {} {line_text}", paint(&number, "1;34", color), paint("|", "1;34", color));
+        let _ = writeln!(out, "{gutter} {} {padding}{}", paint("|", "1;34", color), paint(&"^".repeat(width), "1;31", color));
+        if let Some(help) = &self.help {
+            let _ = writeln!(out, "{gutter} {} help: {help}", paint("=", "1;34", color));
+        }
+        out
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.render(false))
+    }
+}
+
+/// Every diagnostic reported while compiling one source text.
+#[derive(Clone, Debug, Default)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub fn render(&self, color: bool) -> String {
+        self.0.iter().map(|diagnostic| diagnostic.render(color)).collect()
+    }
+}
+
+impl Deref for Diagnostics {
+    type Target = [Diagnostic];
+
+    fn deref(&self) -> &[Diagnostic] {
+        &self.0
+    }
+}
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.render(false))
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+fn paint(text: &str, style: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{style}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
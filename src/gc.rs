@@ -0,0 +1,274 @@
+use std::{cell::Cell, fmt::Display, io::{self, Write}, ops::Deref, ptr::NonNull};
+
+use crate::value::*;
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+pub trait Trace {
+    fn trace(&self, heap: &mut Heap);
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+
+    #[cfg(feature = "debug_log_gc")]
+    fn kind(&self) -> &'static str {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
+}
+
+impl Trace for () {
+    fn trace(&self, _heap: &mut Heap) {}
+}
+
+impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, heap: &mut Heap) {
+        for item in self {
+            item.trace(heap);
+        }
+    }
+}
+
+struct GcBox<T: ?Sized> {
+    marked: Cell<bool>,
+    size: Cell<usize>,
+    value: T,
+}
+
+pub struct Gc<T: ?Sized> {
+    ptr: NonNull<GcBox<T>>,
+}
+
+impl<T: ?Sized> Gc<T> {
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        std::ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+    }
+
+    pub fn as_ptr(&self) -> *const T {
+        unsafe { &self.ptr.as_ref().value }
+    }
+
+    pub fn is_marked(&self) -> bool {
+        unsafe { self.ptr.as_ref() }.marked.get()
+    }
+}
+
+impl<T: ?Sized> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for Gc<T> {}
+
+impl<T: ?Sized> Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Handles never leave the crate, and the VM only dereferences the ones it can
+        // still reach from a root, so the object has not been swept.
+        unsafe { &self.ptr.as_ref().value }
+    }
+}
+
+impl<T: ?Sized + Display> Display for Gc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GcStats {
+    pub bytes_allocated: usize,
+    pub next_gc: usize,
+    pub objects: usize,
+    pub collections: usize,
+    pub bytes_freed: usize,
+}
+
+pub struct Heap {
+    objects: Vec<NonNull<GcBox<dyn Trace>>>,
+    gray: Vec<NonNull<GcBox<dyn Trace>>>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    collections: usize,
+    bytes_freed: usize,
+    /// Receives the `debug_log_gc`, `debug_trace_execution` and `debug_print_code` output.
+    pub trace: Box<dyn Write>,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            gray: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            collections: 0,
+            bytes_freed: 0,
+            trace: Box::new(io::stdout()),
+        }
+    }
+
+    pub fn alloc<T: Trace + 'static>(&mut self, value: T) -> Gc<T> {
+        let size = value.size();
+        let boxed = Box::new(GcBox { marked: Cell::new(false), size: Cell::new(size), value });
+        let ptr = NonNull::from(Box::leak(boxed));
+
+        #[cfg(feature = "debug_log_gc")]
+        let _ = writeln!(self.trace, "{:p} allocate {size} for {}", ptr.cast::<()>(), unsafe { ptr.as_ref() }.value.kind());
+
+        self.objects.push(ptr);
+        self.bytes_allocated += size;
+        Gc { ptr }
+    }
+
+    /// Re-measures an object whose tables grew after it was allocated.
+    pub fn update_size<T: Trace + 'static>(&mut self, object: Gc<T>) {
+        let gc_box = unsafe { object.ptr.as_ref() };
+        let size = gc_box.value.size();
+        self.bytes_allocated = self.bytes_allocated - gc_box.size.replace(size) + size;
+    }
+
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "debug_stress_gc") || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn mark_object<T: Trace + 'static>(&mut self, object: Gc<T>) {
+        let gc_box = unsafe { object.ptr.as_ref() };
+        if gc_box.marked.replace(true) {
+            return;
+        }
+
+        #[cfg(feature = "debug_log_gc")]
+        let _ = writeln!(self.trace, "{:p} mark {} for {}", object.ptr.cast::<()>(), gc_box.size.get(), gc_box.value.kind());
+
+        self.gray.push(object.ptr);
+    }
+
+    pub fn mark_value(&mut self, value: &Value) {
+        match value {
+            Value::String(x) => self.mark_object(*x),
+            Value::Function(x) => self.mark_object(*x),
+            Value::Closure(x) => self.mark_object(*x),
+            Value::Native(x) => self.mark_object(*x),
+            Value::Class(x) => self.mark_object(*x),
+            Value::Instance(x) => self.mark_object(*x),
+            Value::BoundMethod(x) => self.mark_object(*x),
+            Value::Bool(_) | Value::Nil | Value::Number(_) => (),
+        }
+    }
+
+    pub fn trace_references(&mut self) {
+        while let Some(ptr) = self.gray.pop() {
+            let gc_box = unsafe { ptr.as_ref() };
+
+            #[cfg(feature = "debug_log_gc")]
+            let _ = writeln!(self.trace, "{:p} blacken {} for {}", ptr.cast::<()>(), gc_box.size.get(), gc_box.value.kind());
+
+            gc_box.value.trace(self);
+        }
+    }
+
+    pub fn sweep(&mut self) {
+        let mut freed = 0;
+        self.objects.retain(|ptr| {
+            let gc_box = unsafe { ptr.as_ref() };
+            if gc_box.marked.replace(false) {
+                return true;
+            }
+
+            #[cfg(feature = "debug_log_gc")]
+            let _ = writeln!(self.trace, "{:p} free {} for {}", ptr.cast::<()>(), gc_box.size.get(), gc_box.value.kind());
+
+            freed += gc_box.size.get();
+            drop(unsafe { Box::from_raw(ptr.as_ptr()) });
+            false
+        });
+
+        self.bytes_allocated -= freed;
+        self.bytes_freed += freed;
+        self.collections += 1;
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+    }
+
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            bytes_allocated: self.bytes_allocated,
+            next_gc: self.next_gc,
+            objects: self.objects.len(),
+            collections: self.collections,
+            bytes_freed: self.bytes_freed,
+        }
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for ptr in self.objects.drain(..) {
+            drop(unsafe { Box::from_raw(ptr.as_ptr()) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{ObjClass, ObjString};
+
+    fn string(heap: &mut Heap, chars: &str) -> Gc<ObjString> {
+        heap.alloc(ObjString::new(chars.to_string(), 0))
+    }
+
+    #[test]
+    fn sweep_frees_unmarked_objects() {
+        let mut heap = Heap::new();
+        let live = string(&mut heap, "live");
+        let dead = string(&mut heap, "dead");
+        let dead_size = dead.size();
+        let before = heap.stats();
+
+        heap.mark_object(live);
+        heap.trace_references();
+        heap.sweep();
+
+        let after = heap.stats();
+        assert_eq!(after.objects, 1);
+        assert_eq!(after.bytes_allocated, before.bytes_allocated - dead_size);
+        assert_eq!(after.bytes_freed, dead_size);
+        assert_eq!(after.collections, 1);
+        assert_eq!(live.chars, "live");
+
+        // Sweeping clears the marks, so an unmarked second pass frees everything.
+        heap.sweep();
+        assert_eq!(heap.stats().objects, 0);
+    }
+
+    #[test]
+    fn sweep_sets_the_next_threshold() {
+        let mut heap = Heap::new();
+        string(&mut heap, "garbage");
+        heap.sweep();
+
+        assert_eq!(heap.stats().objects, 0);
+        assert_eq!(heap.stats().bytes_allocated, 0);
+        assert_eq!(heap.stats().next_gc, GC_INITIAL_THRESHOLD);
+    }
+
+    #[test]
+    fn update_size_tracks_growth() {
+        let mut heap = Heap::new();
+        let name = string(&mut heap, "Bag");
+        let class = heap.alloc(ObjClass::new(name));
+        let before = heap.stats().bytes_allocated;
+
+        class.methods.borrow_mut().set(name, Value::Nil);
+        heap.update_size(class);
+
+        let grown = class.methods.borrow().heap_size();
+        assert!(grown > 0);
+        assert_eq!(heap.stats().bytes_allocated, before + grown);
+    }
+}
@@ -0,0 +1,52 @@
+use std::fmt::Display;
+
+use crate::value;
+
+/// A Lox value copied out of the VM. It owns its data, so the host can keep it
+/// around after the object it was read from has been collected.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    /// Functions, classes and instances, as `print` would show them. This is
+    /// only a description: it can't be handed back to the VM, and two objects
+    /// never compare equal, since their text says nothing about identity.
+    Object(String),
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Nil, Self::Nil) => true,
+            (Self::Bool(a), Self::Bool(b)) => a == b,
+            (Self::Number(a), Self::Number(b)) => a == b,
+            (Self::String(a), Self::String(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl From<&value::Value> for Value {
+    fn from(value: &value::Value) -> Self {
+        match value {
+            value::Value::Nil => Self::Nil,
+            value::Value::Bool(x) => Self::Bool(*x),
+            value::Value::Number(x) => Self::Number(*x),
+            value::Value::String(x) => Self::String(x.chars.clone()),
+            object => Self::Object(object.to_string()),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Nil => write!(f, "nil"),
+            Self::Bool(x) => write!(f, "{x}"),
+            Self::Number(x) => write!(f, "{x}"),
+            Self::String(x) | Self::Object(x) => write!(f, "{x}"),
+        }
+    }
+}
@@ -0,0 +1,24 @@
+#[macro_use]
+mod chunk;
+
+#[macro_use]
+mod value;
+
+mod gc;
+mod object;
+mod vm;
+mod compiler;
+mod scanner;
+mod table;
+mod diagnostics;
+mod script;
+mod host;
+
+pub use chunk::Chunk;
+pub use diagnostics::{Diagnostic, Diagnostics};
+pub use gc::GcStats;
+pub use host::Value;
+pub use object::NativeFn;
+pub use scanner::Span;
+pub use script::{compile, CompiledScript};
+pub use vm::{InterpretError, InterpretResult, RuntimeError, TraceFrame, VM};
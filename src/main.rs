@@ -1,19 +1,10 @@
-#[macro_use]
-mod chunk;
+use std::io::{Write, BufRead, IsTerminal};
 
-#[macro_use]
-mod value;
-
-mod vm;
-mod compiler;
-mod scanner;
-
-use std::io::{Write, BufRead};
-
-use vm::*;
+use lox_vm::{InterpretError, VM};
 
 fn main() {
     let mut vm = VM::new();
+    vm.set_diagnostics(Box::new(std::io::stderr()), std::io::stderr().is_terminal());
 
     let args: Vec<String> = std::env::args().collect();
 
@@ -40,7 +31,7 @@ fn repl(vm: &mut VM) {
             break;
         }
 
-        let _ = vm.interpret(&line); 
+        let _ = vm.interpret(&line);
     }
 }
 
@@ -49,8 +40,8 @@ fn run_file(path: &str, vm: &mut VM) {
     let result = vm.interpret(&source);
 
     match result {
-        Err(InterpretError::CompilerError) => std::process::exit(65),
-        Err(InterpretError::RuntimeError) => std::process::exit(70),
+        Err(InterpretError::CompilerError(_)) => std::process::exit(65),
+        Err(InterpretError::RuntimeError(_)) => std::process::exit(70),
         _ => {}
     }
 }
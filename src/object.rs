@@ -0,0 +1,232 @@
+use std::{cell::RefCell, fmt::Display, rc::Rc};
+
+use crate::{chunk::*, gc::*, host, table::*, value::*, vm::VM};
+
+pub struct ObjString {
+    pub chars: String,
+    pub hash: u32,
+}
+
+impl ObjString {
+    pub fn new(chars: String, hash: u32) -> Self {
+        Self { chars, hash }
+    }
+}
+
+impl Trace for ObjString {
+    fn trace(&self, _heap: &mut Heap) {}
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.chars.capacity()
+    }
+}
+
+impl Display for ObjString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.chars)
+    }
+}
+
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub name: Option<Gc<ObjString>>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<Gc<ObjString>>, source: Rc<str>) -> Self {
+        Self { arity: 0, upvalue_count: 0, chunk: Chunk::new(source), name }
+    }
+}
+
+impl Trace for ObjFunction {
+    fn trace(&self, heap: &mut Heap) {
+        if let Some(name) = self.name {
+            heap.mark_object(name);
+        }
+        self.chunk.trace(heap);
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.chunk.heap_size()
+    }
+}
+
+impl Display for ObjFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {name}>"),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+pub enum UpvalueState {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct ObjUpvalue {
+    pub state: RefCell<UpvalueState>,
+}
+
+impl ObjUpvalue {
+    pub fn new(slot: usize) -> Self {
+        Self { state: RefCell::new(UpvalueState::Open(slot)) }
+    }
+
+    pub fn open_slot(&self) -> Option<usize> {
+        match *self.state.borrow() {
+            UpvalueState::Open(slot) => Some(slot),
+            UpvalueState::Closed(_) => None,
+        }
+    }
+}
+
+impl Trace for ObjUpvalue {
+    fn trace(&self, heap: &mut Heap) {
+        if let UpvalueState::Closed(value) = &*self.state.borrow() {
+            heap.mark_value(value);
+        }
+    }
+}
+
+pub struct ObjClosure {
+    pub function: Gc<ObjFunction>,
+    pub upvalues: Vec<Gc<ObjUpvalue>>,
+}
+
+impl ObjClosure {
+    pub fn new(function: Gc<ObjFunction>, upvalues: Vec<Gc<ObjUpvalue>>) -> Self {
+        Self { function, upvalues }
+    }
+}
+
+impl Trace for ObjClosure {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(self.function);
+        for upvalue in &self.upvalues {
+            heap.mark_object(*upvalue);
+        }
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.upvalues.capacity() * std::mem::size_of::<Gc<ObjUpvalue>>()
+    }
+}
+
+impl Display for ObjClosure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.function)
+    }
+}
+
+/// A host function callable from Lox. Arguments arrive as owned copies, so
+/// functions, classes and instances are only seen as their `print` text, and
+/// returning `Value::Object` is a runtime error.
+pub type NativeFn = fn(&mut VM, &[host::Value]) -> Result<host::Value, String>;
+
+pub struct ObjNative {
+    pub name: Gc<ObjString>,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl ObjNative {
+    pub fn new(name: Gc<ObjString>, arity: usize, function: NativeFn) -> Self {
+        Self { name, arity, function }
+    }
+}
+
+impl Trace for ObjNative {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(self.name);
+    }
+}
+
+impl Display for ObjNative {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+pub struct ObjClass {
+    pub name: Gc<ObjString>,
+    pub methods: RefCell<Table>,
+}
+
+impl ObjClass {
+    pub fn new(name: Gc<ObjString>) -> Self {
+        Self { name, methods: RefCell::new(Table::new()) }
+    }
+}
+
+impl Trace for ObjClass {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(self.name);
+        self.methods.borrow().trace(heap);
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.methods.borrow().heap_size()
+    }
+}
+
+impl Display for ObjClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+pub struct ObjInstance {
+    pub class: Gc<ObjClass>,
+    pub fields: RefCell<Table>,
+}
+
+impl ObjInstance {
+    pub fn new(class: Gc<ObjClass>) -> Self {
+        Self { class, fields: RefCell::new(Table::new()) }
+    }
+}
+
+impl Trace for ObjInstance {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(self.class);
+        self.fields.borrow().trace(heap);
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.fields.borrow().heap_size()
+    }
+}
+
+impl Display for ObjInstance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} instance", self.class.name)
+    }
+}
+
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: Gc<ObjClosure>,
+}
+
+impl ObjBoundMethod {
+    pub fn new(receiver: Value, method: Gc<ObjClosure>) -> Self {
+        Self { receiver, method }
+    }
+}
+
+impl Trace for ObjBoundMethod {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_value(&self.receiver);
+        heap.mark_object(self.method);
+    }
+}
+
+impl Display for ObjBoundMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.method)
+    }
+}
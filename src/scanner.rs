@@ -20,6 +20,20 @@ pub struct Scanner {
     start: usize,
     current: usize,
     line: usize,
+    line_start: usize,
+    start_line: usize,
+    start_column: usize,
+    start_byte: usize,
+    current_byte: usize,
+}
+
+/// A region of source text: `offset` is in bytes, `column` and `length` in characters.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Span {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
 }
 
 #[derive(Clone, Default, Debug)]
@@ -27,6 +41,15 @@ pub struct Token {
     pub t: TokenType,
     pub lexme: String,
     pub line: usize,
+    pub offset: usize,
+    pub column: usize,
+    pub length: usize,
+}
+
+impl Token {
+    pub fn span(&self) -> Span {
+        Span { offset: self.offset, line: self.line, column: self.column, length: self.length }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Default, Copy, Sequence)]
@@ -77,13 +100,26 @@ pub enum TokenType {
 
 impl Scanner {
     pub fn new(source: &str) -> Self {
-        Self {source: source.chars().collect(), start: 0, current: 0, line: 1}
+        Self {
+            source: source.chars().collect(),
+            start: 0,
+            current: 0,
+            line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
+            start_byte: 0,
+            current_byte: 0,
+        }
     }
 
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.start - self.line_start + 1;
+        self.start_byte = self.current_byte;
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -120,22 +156,40 @@ impl Scanner {
     }
 
     fn make_token(&self, kind: TokenType) -> Token {
-        Token {t: kind, lexme: self.source[self.start..self.current].iter().collect::<String>(), line: self.line}
+        self.token(kind, self.source[self.start..self.current].iter().collect::<String>())
     }
 
     fn error_token(&self, message: &str) -> Token {
-        Token {t: TokenType::Error, lexme: message.to_string(), line: self.line}
+        self.token(TokenType::Error, message.to_string())
+    }
+
+    fn token(&self, t: TokenType, lexme: String) -> Token {
+        Token {
+            t,
+            lexme,
+            line: self.start_line,
+            offset: self.start_byte,
+            column: self.start_column,
+            length: self.current - self.start,
+        }
     }
 
     fn advance(&mut self) -> char {
+        let c = self.source[self.current];
         self.current += 1;
-        self.source[self.current - 1]
+        self.current_byte += c.len_utf8();
+
+        if c == '\n' {
+            self.line += 1;
+            self.line_start = self.current;
+        }
+        c
     }
 
     fn is_match(&mut self, expected: char) -> bool {
         if self.is_at_end() || self.source[self.current] != expected {false}
         else {
-            self.current += 1;
+            self.advance();
             true
         }
     }
@@ -143,11 +197,7 @@ impl Scanner {
     fn skip_whitespace(&mut self) {
         loop {
             match self.peek() {
-                Some(' ' | '\r' | '\t') => {self.advance();},
-                Some('\n') => {
-                    self.line += 1;
-                    self.advance();
-                },
+                Some(' ' | '\r' | '\t' | '\n') => {self.advance();},
                 Some('/') => if let Some('/') = self.peek_next() {
                     while self.peek() != Some('\n') && !self.is_at_end() {self.advance();}
                 } else {
@@ -191,7 +241,7 @@ impl Scanner {
 
     fn make_identifier(&mut self) -> Token{
         let lexme: String = self.source[self.start..self.current].iter().collect();
-        Token {t: self.identifier_type(&lexme), lexme, line: self.line}
+        self.token(self.identifier_type(&lexme), lexme)
     }
 
     fn number(&mut self) -> Token {
@@ -209,7 +259,6 @@ impl Scanner {
 
     fn string(&mut self) -> Token {
         while self.peek() != Some('"') && !self.is_at_end() {
-            if let Some('\n') = self.peek() {self.line += 1;}
             self.advance();
         }
 
@@ -226,6 +275,55 @@ impl Scanner {
     }
 
     fn peek_next(&self) -> Option<char> {
-        if self.is_at_end() {None} else {Some(self.source[self.current + 1])}
+        self.source.get(self.current + 1).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        let mut scanner = Scanner::new(source);
+        let mut tokens = Vec::new();
+        loop {
+            let token = scanner.scan_token();
+            let done = token.t == TokenType::Eof;
+            tokens.push(token);
+            if done {
+                return tokens;
+            }
+        }
+    }
+
+    #[test]
+    fn tokens_carry_lines_and_columns() {
+        let tokens = tokens("var x = 1;\n  print \"é\" + x;");
+        let print = &tokens[5];
+        assert_eq!(print.t, TokenType::Print);
+        assert_eq!(print.span(), Span { offset: 13, line: 2, column: 3, length: 5 });
+
+        let string = &tokens[6];
+        assert_eq!(string.t, TokenType::String);
+        assert_eq!(string.span(), Span { offset: 19, line: 2, column: 9, length: 3 });
+
+        let plus = &tokens[7];
+        assert_eq!((plus.offset, plus.column), (24, 13));
+    }
+
+    #[test]
+    fn number_at_end_of_input() {
+        let tokens = tokens("1.");
+        assert_eq!(tokens[0].t, TokenType::Number);
+        assert_eq!(tokens[0].lexme, "1");
+        assert_eq!(tokens[1].t, TokenType::Dot);
+    }
+
+    #[test]
+    fn unterminated_string_is_an_error() {
+        let tokens = tokens("\"abc\ndef");
+        assert_eq!(tokens[0].t, TokenType::Error);
+        assert_eq!(tokens[0].lexme, "Unterminated string.");
+        assert_eq!((tokens[0].line, tokens[0].column), (1, 1));
     }
 }
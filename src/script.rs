@@ -0,0 +1,70 @@
+use std::{io, rc::Rc};
+
+use crate::{chunk::Chunk, compiler::{Allocator, Compiler}, diagnostics::Diagnostics, gc::*, object::*, table::*, value::Value};
+
+/// A compiled script together with the private heap its objects live on.
+/// `VM::run_compiled` copies it into the running VM the first time it runs
+/// there, so it can be executed any number of times without scanning or
+/// parsing the source again.
+pub struct CompiledScript {
+    // Owns the objects `function` points at. Nothing is allocated on it after
+    // compiling, so the function never needs to be rooted.
+    _heap: Heap,
+    function: Gc<ObjFunction>,
+    // VMs hold weak references to this to know when to drop their copy.
+    token: Rc<()>,
+}
+
+impl CompiledScript {
+    pub(crate) fn function(&self) -> &ObjFunction {
+        &self.function
+    }
+
+    /// The bytecode of the script's top level, for inspecting or disassembling it.
+    pub fn chunk(&self) -> &Chunk {
+        &self.function.chunk
+    }
+
+    pub(crate) fn token(&self) -> &Rc<()> {
+        &self.token
+    }
+}
+
+/// The heap a script is compiled onto. It never collects: whatever the compiler
+/// allocates is either reachable from the script or freed with the heap.
+struct ScriptHeap {
+    heap: Heap,
+    strings: Table,
+}
+
+impl Allocator for ScriptHeap {
+    fn alloc_function(&mut self, function: ObjFunction, _roots: &dyn Trace) -> Gc<ObjFunction> {
+        self.heap.alloc(function)
+    }
+
+    fn intern(&mut self, chars: String, _roots: &dyn Trace) -> Gc<ObjString> {
+        let hash = hash_string(&chars);
+        if let Some(interned) = self.strings.find_string(&chars, hash) {
+            return interned;
+        }
+
+        let string = self.heap.alloc(ObjString::new(chars, hash));
+        self.strings.set(string, Value::Nil);
+        string
+    }
+
+    // There is no host to show the code to yet; it is printed when a VM loads it.
+    #[cfg(feature = "debug_print_code")]
+    fn trace_sink(&mut self) -> &mut dyn io::Write {
+        &mut self.heap.trace
+    }
+}
+
+pub fn compile(source: &str) -> Result<CompiledScript, Diagnostics> {
+    let mut heap = Heap::new();
+    heap.trace = Box::new(io::sink());
+    let mut script_heap = ScriptHeap { heap, strings: Table::new() };
+
+    let function = Compiler::new(source, &mut script_heap).compile()?;
+    Ok(CompiledScript { _heap: script_heap.heap, function, token: Rc::new(()) })
+}
@@ -0,0 +1,263 @@
+use crate::{gc::*, object::*, value::*};
+
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+pub fn hash_string(chars: &str) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for byte in chars.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+#[derive(Clone, Copy)]
+enum Entry {
+    Empty,
+    Tombstone,
+    Occupied(Gc<ObjString>, Value),
+}
+
+pub struct Table {
+    count: usize,
+    entries: Vec<Entry>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self { count: 0, entries: Vec::new() }
+    }
+
+    pub fn get(&self, key: Gc<ObjString>) -> Option<Value> {
+        if self.count == 0 {
+            return None;
+        }
+
+        match self.entries[find_entry(&self.entries, key)] {
+            Entry::Occupied(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn set(&mut self, key: Gc<ObjString>, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.entries.len() as f64 * TABLE_MAX_LOAD {
+            let capacity = if self.entries.len() < 8 { 8 } else { self.entries.len() * 2 };
+            self.adjust_capacity(capacity);
+        }
+
+        let index = find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        let is_new_key = !matches!(entry, Entry::Occupied(..));
+        if matches!(entry, Entry::Empty) {
+            self.count += 1;
+        }
+
+        *entry = Entry::Occupied(key, value);
+        is_new_key
+    }
+
+    pub fn delete(&mut self, key: Gc<ObjString>) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+
+        let index = find_entry(&self.entries, key);
+        if !matches!(self.entries[index], Entry::Occupied(..)) {
+            return false;
+        }
+
+        // Leave a tombstone so that probe sequences running through this slot keep going.
+        self.entries[index] = Entry::Tombstone;
+        true
+    }
+
+    pub fn add_all(&self, to: &mut Table) {
+        for entry in &self.entries {
+            if let Entry::Occupied(key, value) = *entry {
+                to.set(key, value);
+            }
+        }
+    }
+
+    pub fn find_string(&self, chars: &str, hash: u32) -> Option<Gc<ObjString>> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let mut index = hash as usize & (self.entries.len() - 1);
+        loop {
+            match self.entries[index] {
+                Entry::Empty => return None,
+                Entry::Occupied(key, _) if key.hash == hash && key.chars == chars => return Some(key),
+                _ => (),
+            }
+
+            index = (index + 1) & (self.entries.len() - 1);
+        }
+    }
+
+    /// Drops every entry whose key was not reached by the current collection, so
+    /// the intern table never keeps a string alive on its own.
+    pub fn remove_white(&mut self) {
+        for entry in self.entries.iter_mut() {
+            if let Entry::Occupied(key, _) = *entry {
+                if !key.is_marked() {
+                    *entry = Entry::Tombstone;
+                }
+            }
+        }
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.entries.capacity() * std::mem::size_of::<Entry>()
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let mut entries = vec![Entry::Empty; capacity];
+
+        self.count = 0;
+        for entry in &self.entries {
+            if let Entry::Occupied(key, value) = *entry {
+                let index = find_entry(&entries, key);
+                entries[index] = Entry::Occupied(key, value);
+                self.count += 1;
+            }
+        }
+
+        self.entries = entries;
+    }
+}
+
+impl Trace for Table {
+    fn trace(&self, heap: &mut Heap) {
+        for entry in &self.entries {
+            if let Entry::Occupied(key, value) = entry {
+                heap.mark_object(*key);
+                heap.mark_value(value);
+            }
+        }
+    }
+}
+
+fn find_entry(entries: &[Entry], key: Gc<ObjString>) -> usize {
+    let mut index = key.hash as usize & (entries.len() - 1);
+    let mut tombstone = None;
+
+    loop {
+        match entries[index] {
+            Entry::Empty => return tombstone.unwrap_or(index),
+            Entry::Tombstone => {
+                tombstone.get_or_insert(index);
+            }
+            Entry::Occupied(k, _) if Gc::ptr_eq(&k, &key) => return index,
+            Entry::Occupied(..) => (),
+        }
+
+        index = (index + 1) & (entries.len() - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(heap: &mut Heap, chars: &str, hash: u32) -> Gc<ObjString> {
+        heap.alloc(ObjString::new(chars.to_string(), hash))
+    }
+
+    #[test]
+    fn set_reports_new_keys() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let a = key(&mut heap, "a", hash_string("a"));
+
+        assert!(table.set(a, Value::Number(1.0)));
+        assert!(!table.set(a, Value::Number(2.0)));
+        assert!(matches!(table.get(a), Some(Value::Number(n)) if n == 2.0));
+    }
+
+    #[test]
+    fn delete_keeps_probe_sequences_intact() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let (a, b, c) = (key(&mut heap, "a", 0), key(&mut heap, "b", 0), key(&mut heap, "c", 0));
+        for k in [a, b, c] {
+            table.set(k, Value::Nil);
+        }
+
+        assert!(table.delete(b));
+        assert!(!table.delete(b));
+        assert!(table.get(b).is_none());
+        assert!(table.get(c).is_some());
+    }
+
+    #[test]
+    fn tombstones_are_reused() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let (a, b, d) = (key(&mut heap, "a", 0), key(&mut heap, "b", 0), key(&mut heap, "d", 0));
+        table.set(a, Value::Nil);
+        table.set(b, Value::Nil);
+        let slot = find_entry(&table.entries, a);
+        table.delete(a);
+
+        let count = table.count;
+        assert!(table.set(d, Value::Nil));
+        assert_eq!(table.count, count);
+        assert!(matches!(table.entries[slot], Entry::Occupied(k, _) if Gc::ptr_eq(&k, &d)));
+    }
+
+    #[test]
+    fn resizing_drops_tombstones() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let keys: Vec<_> = (0..6).map(|i| key(&mut heap, &i.to_string(), i % 2)).collect();
+        for k in &keys {
+            table.set(*k, Value::Nil);
+        }
+        for k in &keys[..3] {
+            table.delete(*k);
+        }
+        assert_eq!(table.entries.len(), 8);
+
+        let more: Vec<_> = (6..10).map(|i| key(&mut heap, &i.to_string(), i % 2)).collect();
+        for k in &more {
+            table.set(*k, Value::Nil);
+        }
+
+        assert_eq!(table.entries.len(), 16);
+        assert_eq!(table.count, 7);
+        assert!(keys[..3].iter().all(|k| table.get(*k).is_none()));
+        assert!(keys[3..].iter().chain(&more).all(|k| table.get(*k).is_some()));
+    }
+
+    #[test]
+    fn find_string_probes_past_collisions_and_tombstones() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let (a, b) = (key(&mut heap, "a", 7), key(&mut heap, "b", 7));
+        table.set(a, Value::Nil);
+        table.set(b, Value::Nil);
+        table.delete(a);
+
+        assert!(table.find_string("b", 7).is_some_and(|found| Gc::ptr_eq(&found, &b)));
+        assert!(table.find_string("a", 7).is_none());
+        assert!(table.find_string("b", 8).is_none());
+    }
+
+    #[test]
+    fn remove_white_drops_unmarked_keys() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let (live, dead) = (key(&mut heap, "live", 1), key(&mut heap, "dead", 2));
+        table.set(live, Value::Nil);
+        table.set(dead, Value::Nil);
+
+        heap.mark_object(live);
+        table.remove_white();
+
+        assert!(table.get(live).is_some());
+        assert!(table.get(dead).is_none());
+        assert!(table.find_string("dead", 2).is_none());
+    }
+}
@@ -1,5 +1,7 @@
 use std::fmt::Display;
 
+use crate::{gc::*, object::*};
+
 macro_rules! bool_val {
     ($value: expr) => {
         Value::Bool($value)
@@ -18,30 +20,6 @@ macro_rules! number_val {
     };
 }
 
-macro_rules! as_bool {
-    ($value: expr) => {
-        {
-            if let Value::Bool(x) = $value {
-                x
-            } else {
-                panic!("Not bool.")
-            }
-        }
-    };
-}
-
-macro_rules! as_number {
-    ($value: expr) => {
-        {
-            if let Value::Number(x) = $value {
-                x
-            } else {
-                panic!("Not number.")
-            }
-        }
-    };
-}
-
 macro_rules! is_falsey {
     ($value: expr) => {
         if let Value::Nil | Value::Bool(false) = $value {
@@ -52,11 +30,36 @@ macro_rules! is_falsey {
     };
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy)]
 pub enum Value {
     Bool(bool),
     Nil,
     Number(f64),
+    String(Gc<ObjString>),
+    Function(Gc<ObjFunction>),
+    Closure(Gc<ObjClosure>),
+    Native(Gc<ObjNative>),
+    Class(Gc<ObjClass>),
+    Instance(Gc<ObjInstance>),
+    BoundMethod(Gc<ObjBoundMethod>),
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Bool(a), Self::Bool(b)) => a == b,
+            (Self::Nil, Self::Nil) => true,
+            (Self::Number(a), Self::Number(b)) => a == b,
+            (Self::String(a), Self::String(b)) => Gc::ptr_eq(a, b),
+            (Self::Function(a), Self::Function(b)) => Gc::ptr_eq(a, b),
+            (Self::Closure(a), Self::Closure(b)) => Gc::ptr_eq(a, b),
+            (Self::Native(a), Self::Native(b)) => Gc::ptr_eq(a, b),
+            (Self::Class(a), Self::Class(b)) => Gc::ptr_eq(a, b),
+            (Self::Instance(a), Self::Instance(b)) => Gc::ptr_eq(a, b),
+            (Self::BoundMethod(a), Self::BoundMethod(b)) => Gc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
 }
 
 impl Display for Value {
@@ -65,6 +68,13 @@ impl Display for Value {
             Self::Bool(x) => write!(f, "{x}"),
             Self::Number(x) => write!(f, "{x}"),
             Self::Nil => write!(f, "nil"),
+            Self::String(x) => write!(f, "{x}"),
+            Self::Function(x) => write!(f, "{x}"),
+            Self::Closure(x) => write!(f, "{x}"),
+            Self::Native(x) => write!(f, "{x}"),
+            Self::Class(x) => write!(f, "{x}"),
+            Self::Instance(x) => write!(f, "{x}"),
+            Self::BoundMethod(x) => write!(f, "{x}"),
         }
     }
 }
@@ -90,4 +100,16 @@ impl ValueArray {
     pub fn get(&self, seq: usize) -> Value {
         self.values[seq]
     }
+
+    pub fn heap_size(&self) -> usize {
+        self.values.capacity() * std::mem::size_of::<Value>()
+    }
+}
+
+impl Trace for ValueArray {
+    fn trace(&self, heap: &mut Heap) {
+        for value in &self.values {
+            heap.mark_value(value);
+        }
+    }
 }
\ No newline at end of file
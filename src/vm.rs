@@ -1,8 +1,8 @@
 
 
-use std::{rc::Rc};
+use std::{fmt::Display, io::{self, Write}, rc::{Rc, Weak}};
 
-use crate::{chunk::*, value::Value, compiler::*};
+use crate::{chunk::*, value::Value, host, compiler::*, object::*, gc::*, table::*, diagnostics::*, script::CompiledScript};
 
 macro_rules! binary_op {
     ($self: expr, $value_type: ident, $op: tt) => {{
@@ -16,54 +16,515 @@ macro_rules! binary_op {
         }
     }};
 }
+#[derive(Debug)]
 pub enum InterpretError {
-    CompilerError,
-    RuntimeError,
+    CompilerError(Diagnostics),
+    RuntimeError(Box<RuntimeError>),
+}
+
+impl InterpretError {
+    pub fn render(&self, color: bool) -> String {
+        match self {
+            InterpretError::CompilerError(diagnostics) => diagnostics.render(color),
+            InterpretError::RuntimeError(error) => error.render(color),
+        }
+    }
+}
+
+impl Display for InterpretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.render(false))
+    }
+}
+
+impl std::error::Error for InterpretError {}
+
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub diagnostic: Diagnostic,
+    pub trace: Vec<TraceFrame>,
+}
+
+impl RuntimeError {
+    pub fn render(&self, color: bool) -> String {
+        let mut out = self.diagnostic.render(color);
+        for frame in &self.trace {
+            out += &format!("{frame}\n");
+        }
+        out
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceFrame {
+    pub function: Option<String>,
+    pub line: usize,
+}
+
+impl Display for TraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {name}()", self.line),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
 }
 
 pub type InterpretResult<T> = Result<T, InterpretError>;
 
+const FRAMES_MAX: usize = 64;
 
-pub struct VM {
+pub struct CallFrame {
+    closure: Gc<ObjClosure>,
     ip: usize,
+    slots: usize,
+}
+
+pub struct VM {
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
-    chunk: Option<Rc<Chunk>>,
+    globals: Table,
+    strings: Table,
+    init_string: Gc<ObjString>,
+    open_upvalues: Vec<Gc<ObjUpvalue>>,
+    /// Functions loaded by `run_compiled`, kept until their script is dropped.
+    scripts: Vec<(Weak<()>, Gc<ObjFunction>)>,
+    heap: Heap,
+    output: Box<dyn Write>,
+    diagnostics: Box<dyn Write>,
+    color: bool,
+}
+
+fn clock_native(_vm: &mut VM, _args: &[host::Value]) -> Result<host::Value, String> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(host::Value::Number(now.as_secs_f64()))
+}
+
+impl Allocator for VM {
+    fn alloc_function(&mut self, function: ObjFunction, roots: &dyn Trace) -> Gc<ObjFunction> {
+        self.alloc_with_roots(function, roots)
+    }
+
+    fn intern(&mut self, chars: String, roots: &dyn Trace) -> Gc<ObjString> {
+        self.alloc_string_with_roots(chars, roots)
+    }
+
+    #[cfg(feature = "debug_print_code")]
+    fn trace_sink(&mut self) -> &mut dyn Write {
+        &mut self.heap.trace
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {   
-        Self { ip: 0, stack: Vec::new(), chunk: None }
+        let mut heap = Heap::new();
+        let mut strings = Table::new();
+
+        let init_string = heap.alloc(ObjString::new("init".to_string(), hash_string("init")));
+        strings.set(init_string, nil_val!());
+
+        let mut vm = Self {
+            frames: Vec::new(),
+            stack: Vec::new(),
+            globals: Table::new(),
+            strings,
+            init_string,
+            open_upvalues: Vec::new(),
+            scripts: Vec::new(),
+            heap,
+            output: Box::new(io::stdout()),
+            diagnostics: Box::new(io::sink()),
+            color: false,
+        };
+        vm.define_native("clock", 0, clock_native);
+        vm
+    }
+
+    /// Redirects the output of `print` statements.
+    pub fn set_output(&mut self, sink: Box<dyn Write>) {
+        self.output = sink;
+    }
+
+    /// Writes rendered compile and runtime errors to `sink`, which discards them by default.
+    /// `color` turns on ANSI styling, which only makes sense for a terminal.
+    pub fn set_diagnostics(&mut self, sink: Box<dyn Write>, color: bool) {
+        self.diagnostics = sink;
+        self.color = color;
+    }
+
+    /// Redirects the `debug_trace_execution`, `debug_print_code` and `debug_log_gc` output.
+    pub fn set_trace(&mut self, sink: Box<dyn Write>) {
+        self.heap.trace = sink;
+    }
+
+    /// Copies out the current value of a global variable. Objects other than
+    /// strings come back as `Value::Object`, a description the VM can't take back.
+    pub fn get_global(&self, name: &str) -> Option<host::Value> {
+        let name = self.strings.find_string(name, hash_string(name))?;
+        self.globals.get(name).as_ref().map(host::Value::from)
+    }
+
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let name = self.alloc_string(name.to_string());
+        let native = self.alloc(ObjNative::new(name, arity, function));
+        self.globals.set(name, Value::Native(native));
+    }
+
+    pub(crate) fn alloc<T: Trace + 'static>(&mut self, object: T) -> Gc<T> {
+        self.alloc_with_roots(object, &())
+    }
+
+    pub(crate) fn alloc_with_roots<T: Trace + 'static, R: Trace + ?Sized>(&mut self, object: T, roots: &R) -> Gc<T> {
+        if self.heap.should_collect() {
+            // The new object is not reachable yet, so keep whatever it points at alive.
+            self.collect(|heap| {
+                roots.trace(heap);
+                object.trace(heap);
+            });
+        }
+
+        self.heap.alloc(object)
+    }
+
+    pub(crate) fn alloc_string(&mut self, chars: String) -> Gc<ObjString> {
+        self.alloc_string_with_roots(chars, &())
+    }
+
+    pub(crate) fn alloc_string_with_roots<R: Trace + ?Sized>(&mut self, chars: String, roots: &R) -> Gc<ObjString> {
+        let hash = hash_string(&chars);
+        if let Some(interned) = self.strings.find_string(&chars, hash) {
+            return interned;
+        }
+
+        let string = self.alloc_with_roots(ObjString::new(chars, hash), roots);
+        self.strings.set(string, nil_val!());
+        string
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    pub fn collect_garbage(&mut self) {
+        self.collect(|_| ());
+    }
+
+    /// Runs a collection, letting `mark_pending` mark objects that are not reachable
+    /// from the roots yet.
+    fn collect(&mut self, mark_pending: impl FnOnce(&mut Heap)) {
+        #[cfg(feature = "debug_log_gc")]
+        let before = {
+            let _ = writeln!(self.heap.trace, "-- gc begin");
+            self.heap.stats().bytes_allocated
+        };
+
+        mark_pending(&mut self.heap);
+        self.mark_roots();
+        self.heap.trace_references();
+        self.strings.remove_white();
+        self.heap.sweep();
+
+        #[cfg(feature = "debug_log_gc")] {
+            let stats = self.heap.stats();
+            let _ = writeln!(self.heap.trace, "-- gc end");
+            let _ = writeln!(self.heap.trace, "   collected {} bytes (from {before} to {}) next at {}",
+                before - stats.bytes_allocated, stats.bytes_allocated, stats.next_gc);
+        }
+    }
+
+    fn mark_roots(&mut self) {
+        for value in &self.stack {
+            self.heap.mark_value(value);
+        }
+
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+
+        for upvalue in &self.open_upvalues {
+            self.heap.mark_object(*upvalue);
+        }
+
+        // A dropped script can't be run again, so its function is garbage.
+        self.scripts.retain(|(script, _)| script.strong_count() > 0);
+        for (_, function) in &self.scripts {
+            self.heap.mark_object(*function);
+        }
+
+        self.globals.trace(&mut self.heap);
+        self.heap.mark_object(self.init_string);
     }
 
     pub fn interpret(&mut self, source: &str) -> InterpretResult<()> {
-        let mut chunk = Chunk::new();
-        let mut compiler = Compiler::new(source, &mut chunk);
-        compiler.compile()?;
-        self.ip = 0;
-        self.run(chunk)
+        let result = Compiler::new(source, self).compile()
+            .map_err(InterpretError::CompilerError)
+            .and_then(|function| self.run_function(function));
+        self.report(result)
+    }
+
+    /// Runs a script produced by `compile`. The script can be run any number of
+    /// times, on this VM or on others; each VM loads it only once.
+    pub fn run_compiled(&mut self, script: &CompiledScript) -> InterpretResult<()> {
+        let function = self.loaded_function(script);
+        let result = self.run_function(function);
+        self.report(result)
+    }
+
+    fn loaded_function(&mut self, script: &CompiledScript) -> Gc<ObjFunction> {
+        let token = Rc::downgrade(script.token());
+        if let Some((_, function)) = self.scripts.iter().find(|(loaded, _)| loaded.ptr_eq(&token)) {
+            return *function;
+        }
+
+        let function = self.load_function(script.function());
+        self.scripts.push((token, function));
+        function
+    }
+
+    /// Copies a function compiled on another heap into this one, interning its
+    /// strings so they compare equal to the ones this VM already holds.
+    fn load_function(&mut self, function: &ObjFunction) -> Gc<ObjFunction> {
+        // Everything allocated here stays on the stack until the function owns it.
+        let base = self.stack.len();
+        let name = function.name.map(|name| {
+            let name = self.alloc_string(name.chars.clone());
+            self.stack.push(Value::String(name));
+            name
+        });
+        let chunk = function.chunk.map_constants(|constant| {
+            let constant = match constant {
+                Value::String(string) => Value::String(self.alloc_string(string.chars.clone())),
+                Value::Function(nested) => Value::Function(self.load_function(&nested)),
+                other => other,
+            };
+            self.stack.push(constant);
+            constant
+        });
+
+        let loaded = self.alloc(ObjFunction { arity: function.arity, upvalue_count: function.upvalue_count, chunk, name });
+        self.stack.truncate(base);
+
+        #[cfg(feature = "debug_print_code")]
+        let _ = loaded.chunk.disassamble(&loaded.to_string(), &mut self.heap.trace);
+
+        loaded
+    }
+
+    fn run_function(&mut self, function: Gc<ObjFunction>) -> InterpretResult<()> {
+        // Natives may run scripts of their own, so only unwind what this call pushed.
+        let depth = self.frames.len();
+        let base = self.stack.len();
+
+        let closure = self.alloc(ObjClosure::new(function, Vec::new()));
+        self.stack.push(Value::Closure(closure));
+        let result = self.call(closure, 0).and_then(|()| self.run(depth));
+        if result.is_err() {
+            self.unwind(depth, base);
+        }
+        result
+    }
+
+    fn report(&mut self, result: InterpretResult<()>) -> InterpretResult<()> {
+        if let Err(error) = &result {
+            let _ = write!(self.diagnostics, "{}", error.render(self.color));
+        }
+        result
     }
 
-    fn run(&mut self, chunk: Chunk) -> InterpretResult<()> {
-        self.load_chunk(chunk);
-        let chunk = self.get_chunk();
+    fn run(&mut self, depth: usize) -> InterpretResult<()> {
         loop {
-            #[cfg(feature = "debug_trace_execution")] {
-                print!("          ");
-                for slot in &self.stack {
-                    print!("[ {slot} ]");
-                }
-                println!();
-                chunk.disassamble_instruction(self.ip);
-            }
+            #[cfg(feature = "debug_trace_execution")]
+            let _ = self.trace_instruction();
 
-            let instruction = self.read_byte(&chunk);
-            match instruction.into() {
+            let instruction: OpCode = self.read_byte().into();
+            let long = instruction.is_long();
+            match instruction {
+                OpCode::Print => {
+                    let _ = writeln!(self.output, "{}", self.stack.pop().unwrap());
+                },
+                OpCode::Jump => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
+                },
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short();
+                    if is_falsey!(self.peek(0)) {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                },
+                OpCode::Loop => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                },
+                OpCode::GetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.upvalues[slot];
+                    let value = match &*upvalue.state.borrow() {
+                        UpvalueState::Open(slot) => self.stack[*slot],
+                        UpvalueState::Closed(value) => *value,
+                    };
+                    self.stack.push(value);
+                },
+                OpCode::SetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.upvalues[slot];
+                    let value = self.peek(0);
+                    match &mut *upvalue.state.borrow_mut() {
+                        UpvalueState::Open(slot) => self.stack[*slot] = value,
+                        UpvalueState::Closed(closed) => *closed = value,
+                    };
+                },
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
+                    let Value::Instance(instance) = self.peek(0) else {
+                        return self.runtime_error("Only instances have properties.");
+                    };
+
+                    let name = self.read_string(long);
+                    let field = instance.fields.borrow().get(name);
+                    match field {
+                        Some(value) => {
+                            self.stack.pop().unwrap();
+                            self.stack.push(value);
+                        }
+                        None => self.bind_method(&instance.class, name)?,
+                    }
+                },
+                OpCode::SetProperty | OpCode::SetPropertyLong => {
+                    let Value::Instance(instance) = self.peek(1) else {
+                        return self.runtime_error("Only instances have fields.");
+                    };
+
+                    let name = self.read_string(long);
+                    instance.fields.borrow_mut().set(name, self.peek(0));
+                    self.heap.update_size(instance);
+                    let value = self.stack.pop().unwrap();
+                    self.stack.pop().unwrap();
+                    self.stack.push(value);
+                },
+                OpCode::GetSuper | OpCode::GetSuperLong => {
+                    let name = self.read_string(long);
+                    let Value::Class(superclass) = self.stack.pop().unwrap() else {
+                        panic!("Not class.")
+                    };
+                    self.bind_method(&superclass, name)?;
+                },
+                OpCode::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    self.call_value(self.peek(arg_count), arg_count)?;
+                },
+                OpCode::Invoke | OpCode::InvokeLong => {
+                    let method = self.read_string(long);
+                    let arg_count = self.read_byte() as usize;
+                    self.invoke(method, arg_count)?;
+                },
+                OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                    let method = self.read_string(long);
+                    let arg_count = self.read_byte() as usize;
+                    let Value::Class(superclass) = self.stack.pop().unwrap() else {
+                        panic!("Not class.")
+                    };
+                    self.invoke_from_class(&superclass, method, arg_count)?;
+                },
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let Value::Function(function) = self.read_constant(long) else {
+                        panic!("Not function.")
+                    };
+
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+                    for _ in 0..function.upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            let slot = self.frame().slots + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(self.frame().closure.upvalues[index]);
+                        }
+                    }
+
+                    let closure = self.alloc(ObjClosure::new(function, upvalues));
+                    self.stack.push(Value::Closure(closure));
+                },
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop().unwrap();
+                },
+                OpCode::Pop => {
+                    self.stack.pop().unwrap();
+                },
+                OpCode::GetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.stack.push(self.stack[slot]);
+                },
+                OpCode::SetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.stack[slot] = self.peek(0);
+                },
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    let name = self.read_string(long);
+                    match self.globals.get(name) {
+                        Some(value) => self.stack.push(value),
+                        None => self.runtime_error_with_help(
+                            &format!("Undefined variable '{name}'."),
+                            &format!("declare it first with `var {name};`"),
+                        )?,
+                    }
+                },
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    let name = self.read_string(long);
+                    let value = self.stack.pop().unwrap();
+                    self.globals.set(name, value);
+                },
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    let name = self.read_string(long);
+                    if self.globals.set(name, self.peek(0)) {
+                        self.globals.delete(name);
+                        self.runtime_error_with_help(
+                            &format!("Undefined variable '{name}'."),
+                            &format!("assignment does not declare a variable; use `var {name}` instead"),
+                        )?;
+                    }
+                },
+                OpCode::Class | OpCode::ClassLong => {
+                    let name = self.read_string(long);
+                    let class = self.alloc(ObjClass::new(name));
+                    self.stack.push(Value::Class(class));
+                },
+                OpCode::Inherit => {
+                    let Value::Class(superclass) = self.peek(1) else {
+                        return self.runtime_error("Superclass must be a class.");
+                    };
+                    if let Value::Class(subclass) = self.peek(0) {
+                        superclass.methods.borrow().add_all(&mut subclass.methods.borrow_mut());
+                        self.heap.update_size(subclass);
+                    }
+                    self.stack.pop().unwrap();
+                },
+                OpCode::Method | OpCode::MethodLong => {
+                    let name = self.read_string(long);
+                    self.define_method(name);
+                },
                 OpCode::Return => {
-                    println!("{}", self.stack.pop().unwrap());
-                    return Ok(());
+                    let result = self.stack.pop().unwrap();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    if self.frames.len() == depth {
+                        return Ok(());
+                    }
+
+                    self.stack.push(result);
                 },
-                OpCode::Constant => {
-                    let constant = self.read_constant(&chunk);
+                OpCode::Constant | OpCode::ConstantLong => {
+                    let constant = self.read_constant(long);
                     self.stack.push(constant);
                 },
                 OpCode::Nil => self.stack.push(nil_val!()),
@@ -88,7 +549,18 @@ impl VM {
                 },
                 OpCode::Greater => binary_op!(self, bool_val, >)?,
                 OpCode::Less => binary_op!(self, bool_val, <)?,
-                OpCode::Add => binary_op!(self, number_val, +)?,
+                OpCode::Add => {
+                    match (self.peek(0), self.peek(1)) {
+                        (Value::String(b), Value::String(a)) => {
+                            self.stack.pop().unwrap();
+                            self.stack.pop().unwrap();
+                            let result = self.alloc_string(format!("{a}{b}"));
+                            self.stack.push(Value::String(result));
+                        }
+                        (Value::Number(_), Value::Number(_)) => binary_op!(self, number_val, +)?,
+                        _ => self.runtime_error("Operands must be two numbers or two strings.")?,
+                    }
+                },
                 OpCode::Subtract => binary_op!(self, number_val, -)?,
                 OpCode::Multiply => binary_op!(self, number_val, *)?,
                 OpCode::Divide => binary_op!(self, number_val, /)?,
@@ -97,43 +569,217 @@ impl VM {
         }
     }
 
-    fn read_byte(&mut self, chunk: &Chunk) -> u8 {
-        let val = chunk.get(self.ip);
-        self.ip += 1;
+    #[cfg(feature = "debug_trace_execution")]
+    fn trace_instruction(&mut self) -> io::Result<()> {
+        write!(self.heap.trace, "          ")?;
+        for slot in &self.stack {
+            write!(self.heap.trace, "[ {slot} ]")?;
+        }
+        writeln!(self.heap.trace)?;
+
+        let frame = self.frames.last().unwrap();
+        frame.closure.function.chunk.disassamble_instruction(frame.ip, &mut self.heap.trace)?;
+        Ok(())
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> InterpretResult<()> {
+        match callee {
+            Value::Closure(closure) => self.call(closure, arg_count),
+            Value::Native(native) => self.call_native(native, arg_count),
+            Value::Class(class) => {
+                let slot = self.stack.len() - arg_count - 1;
+                let initializer = class.methods.borrow().get(self.init_string);
+                let instance = self.alloc(ObjInstance::new(class));
+                self.stack[slot] = Value::Instance(instance);
+
+                if let Some(Value::Closure(initializer)) = initializer {
+                    self.call(initializer, arg_count)
+                } else if arg_count != 0 {
+                    self.runtime_error(&format!("Expected 0 arguments but got {arg_count}."))
+                } else {
+                    Ok(())
+                }
+            }
+            Value::BoundMethod(bound) => {
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = bound.receiver;
+                self.call(bound.method, arg_count)
+            }
+            _ => self.runtime_error("Can only call functions and classes."),
+        }
+    }
+
+    fn invoke(&mut self, name: Gc<ObjString>, arg_count: usize) -> InterpretResult<()> {
+        let Value::Instance(instance) = self.peek(arg_count) else {
+            return self.runtime_error("Only instances have methods.");
+        };
+
+        let field = instance.fields.borrow().get(name);
+        if let Some(value) = field {
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        self.invoke_from_class(&instance.class, name, arg_count)
+    }
+
+    fn invoke_from_class(&mut self, class: &ObjClass, name: Gc<ObjString>, arg_count: usize) -> InterpretResult<()> {
+        let method = class.methods.borrow().get(name);
+        match method {
+            Some(Value::Closure(method)) => self.call(method, arg_count),
+            _ => self.runtime_error(&format!("Undefined property '{name}'.")),
+        }
+    }
+
+    fn bind_method(&mut self, class: &ObjClass, name: Gc<ObjString>) -> InterpretResult<()> {
+        let method = class.methods.borrow().get(name);
+        let Some(Value::Closure(method)) = method else {
+            return self.runtime_error(&format!("Undefined property '{name}'."));
+        };
+
+        let bound = self.alloc(ObjBoundMethod::new(self.peek(0), method));
+        self.stack.pop().unwrap();
+        self.stack.push(Value::BoundMethod(bound));
+        Ok(())
+    }
+
+    fn define_method(&mut self, name: Gc<ObjString>) {
+        let method = self.peek(0);
+        if let Value::Class(class) = self.peek(1) {
+            class.methods.borrow_mut().set(name, method);
+            self.heap.update_size(class);
+        }
+        self.stack.pop().unwrap();
+    }
+
+    fn call(&mut self, closure: Gc<ObjClosure>, arg_count: usize) -> InterpretResult<()> {
+        let arity = closure.function.arity;
+        if arg_count != arity {
+            return self.runtime_error(&format!("Expected {arity} arguments but got {arg_count}."));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return self.runtime_error_with_help("Stack overflow.", &format!("calls are limited to {FRAMES_MAX} frames; check for unbounded recursion"));
+        }
+
+        let slots = self.stack.len() - arg_count - 1;
+        self.frames.push(CallFrame { closure, ip: 0, slots });
+        Ok(())
+    }
+
+    fn call_native(&mut self, native: Gc<ObjNative>, arg_count: usize) -> InterpretResult<()> {
+        if arg_count != native.arity {
+            return self.runtime_error(&format!("Expected {} arguments but got {arg_count}.", native.arity));
+        }
+
+        let args: Vec<host::Value> = self.stack[self.stack.len() - arg_count..].iter().map(host::Value::from).collect();
+        let result = match (native.function)(self, &args) {
+            Ok(host::Value::Nil) => nil_val!(),
+            Ok(host::Value::Bool(x)) => bool_val!(x),
+            Ok(host::Value::Number(x)) => number_val!(x),
+            Ok(host::Value::String(chars)) => Value::String(self.alloc_string(chars)),
+            Ok(host::Value::Object(_)) => return self.runtime_error("Natives can only return nil, booleans, numbers and strings."),
+            Err(message) => return self.runtime_error(&message),
+        };
+        self.stack.truncate(self.stack.len() - arg_count - 1);
+        self.stack.push(result);
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Gc<ObjUpvalue> {
+        if let Some(upvalue) = self.open_upvalues.iter().find(|u| u.open_slot() == Some(slot)) {
+            return *upvalue;
+        }
+
+        let upvalue = self.alloc(ObjUpvalue::new(slot));
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    fn close_upvalues(&mut self, last: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| match upvalue.open_slot() {
+            Some(slot) if slot >= last => {
+                *upvalue.state.borrow_mut() = UpvalueState::Closed(stack[slot]);
+                false
+            }
+            _ => true,
+        });
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let val = frame.closure.function.chunk.get(frame.ip);
+        frame.ip += 1;
         val
     }
 
-    fn read_constant(&mut self, chunk: &Chunk) -> Value {
-        let seq = self.read_byte(chunk);
-        chunk.get_constant(seq as usize)
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte();
+        let low = self.read_byte();
+        u16::from_be_bytes([high, low])
+    }
+
+    fn read_constant(&mut self, long: bool) -> Value {
+        let seq = if long {
+            u32::from_be_bytes([0, self.read_byte(), self.read_byte(), self.read_byte()]) as usize
+        } else {
+            self.read_byte() as usize
+        };
+        self.frame().closure.function.chunk.get_constant(seq)
+    }
+
+    fn read_string(&mut self, long: bool) -> Gc<ObjString> {
+        if let Value::String(name) = self.read_constant(long) {
+            name
+        } else {
+            panic!("Not string.")
+        }
     }
 
     fn peek(&self, distance: usize) -> Value {
         self.stack[self.stack.len() - 1 - distance]
     } 
 
-    fn runtime_error(&mut self, format: &str) -> InterpretResult<()> {
-        eprintln!("{format}");
-        let line = self.get_chunk().lines[self.ip - 1];
-        eprintln!("[line {line}] in script");
-        self.reset_stack();
+    fn runtime_error(&mut self, message: &str) -> InterpretResult<()> {
+        let diagnostic = self.runtime_diagnostic(message);
+        self.fail(diagnostic)
+    }
 
-        Err(InterpretError::RuntimeError)
+    fn runtime_error_with_help(&mut self, message: &str, help: &str) -> InterpretResult<()> {
+        let diagnostic = self.runtime_diagnostic(message).with_help(help);
+        self.fail(diagnostic)
     }
 
-    fn reset_stack(&mut self) {
-        self.stack.clear();
+    fn runtime_diagnostic(&self, message: &str) -> Diagnostic {
+        let chunk = &self.frame().closure.function.chunk;
+        Diagnostic::new(RUNTIME_ERROR, message, chunk.get_span(self.frame().ip - 1), &chunk.source)
     }
 
-    fn load_chunk(&mut self, chunk: Chunk) {
-        self.chunk = Some(Rc::new(chunk))
+    fn fail(&mut self, diagnostic: Diagnostic) -> InterpretResult<()> {
+        let trace = self.frames.iter().rev()
+            .map(|frame| TraceFrame {
+                function: frame.closure.function.name.map(|name| name.chars.clone()),
+                line: frame.closure.function.chunk.get_line(frame.ip - 1),
+            })
+            .collect();
+
+        Err(InterpretError::RuntimeError(Box::new(RuntimeError { diagnostic, trace })))
     }
 
-    fn get_chunk(&self) -> Rc<Chunk> {
-        if let Some(chunk) = &self.chunk {
-            chunk.clone()
-        } else {
-            panic!("No chunk.")
-        }
+    fn unwind(&mut self, depth: usize, base: usize) {
+        // Closures that escaped before the error still need the captured values.
+        self.close_upvalues(base);
+        self.stack.truncate(base);
+        self.frames.truncate(depth);
     }
 }
@@ -0,0 +1,9 @@
+use lox_vm::{Value, VM};
+
+pub fn number(vm: &VM, name: &str) -> f64 {
+    match vm.get_global(name) {
+        Some(Value::Number(n)) => n,
+        Some(other) => panic!("{name} is {other}, not a number"),
+        None => panic!("{name} is not defined"),
+    }
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use common::number;
+use lox_vm::{compile, InterpretError, Value, VM};
+
+#[test]
+fn script_runs_many_times_on_one_vm() {
+    let script = compile("if (runs == nil) runs = 0; runs = runs + 1;").unwrap();
+    let mut vm = VM::new();
+    vm.interpret("var runs = nil;").unwrap();
+
+    for _ in 0..3 {
+        vm.run_compiled(&script).unwrap();
+    }
+    assert_eq!(number(&vm, "runs"), 3.0);
+}
+
+#[test]
+fn script_runs_on_several_vms() {
+    let script = compile("var greeting = \"hello\" + \" \" + name;").unwrap();
+
+    for name in ["ada", "grace"] {
+        let mut vm = VM::new();
+        vm.interpret(&format!("var name = \"{name}\";")).unwrap();
+        vm.run_compiled(&script).unwrap();
+        vm.interpret(&format!("var same = greeting == \"hello {name}\";")).unwrap();
+        assert!(matches!(vm.get_global("same"), Some(Value::Bool(true))));
+    }
+}
+
+#[test]
+fn nested_functions_and_classes_are_loaded() {
+    let script = compile("
+        class Counter {
+            init() { this.count = 0; }
+            inc() {
+                fun step() { return 1; }
+                this.count = this.count + step();
+                return this;
+            }
+        }
+        var total = Counter().inc().inc().inc().count;
+    ").unwrap();
+
+    let mut vm = VM::new();
+    vm.run_compiled(&script).unwrap();
+    assert_eq!(number(&vm, "total"), 3.0);
+    vm.run_compiled(&script).unwrap();
+    assert_eq!(number(&vm, "total"), 3.0);
+}
+
+#[test]
+fn compiled_chunks_can_be_disassembled() {
+    let script = compile("var a = 1;\nprint a + 2;\n").unwrap();
+    let mut out = Vec::new();
+    script.chunk().disassamble("script", &mut out).unwrap();
+
+    let listing = String::from_utf8(out).unwrap();
+    assert!(listing.starts_with("== script =="));
+    assert!(listing.contains("OP_ADD"));
+    assert_eq!(script.chunk().get_line(script.chunk().len() - 1), 3);
+}
+
+#[test]
+fn compile_errors_are_returned_as_diagnostics() {
+    let diagnostics = compile("var = 1;\nprint;\n").err().unwrap();
+
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].message, "Expect variable name.");
+    assert_eq!(diagnostics[1].span.line, 2);
+    assert!(diagnostics.to_string().contains("1 | var = 1;"));
+}
+
+#[test]
+fn runtime_errors_point_into_the_compiled_source() {
+    let script = compile("fun boom() {\n  return -\"x\";\n}\nboom();\n").unwrap();
+    let mut vm = VM::new();
+    let error = vm.run_compiled(&script).unwrap_err();
+
+    let InterpretError::RuntimeError(runtime) = &error else {
+        panic!("expected a runtime error, got:\n{error}");
+    };
+    assert_eq!(runtime.trace[0].to_string(), "[line 2] in boom()");
+    assert!(error.to_string().contains("2 |   return -\"x\";"));
+}
+
+#[test]
+fn each_vm_loads_a_script_once() {
+    let script = compile("fun f() { return \"loaded\"; } var x = f();").unwrap();
+    let mut vm = VM::new();
+    vm.run_compiled(&script).unwrap();
+    vm.collect_garbage();
+    let before = vm.gc_stats().objects;
+
+    // The second run only allocates the closures for the script and `f`.
+    vm.run_compiled(&script).unwrap();
+    assert_eq!(vm.gc_stats().objects, before + 2);
+}
+
+#[test]
+fn dropped_scripts_are_collected() {
+    let mut vm = VM::new();
+    vm.collect_garbage();
+    let before = vm.gc_stats().objects;
+
+    let script = compile("var unused = \"unused\" + \"string\";").unwrap();
+    vm.run_compiled(&script).unwrap();
+    vm.interpret("unused = nil;").unwrap();
+    vm.collect_garbage();
+    assert!(vm.gc_stats().objects > before);
+
+    // Only the name of the global is left once the script is gone.
+    drop(script);
+    vm.collect_garbage();
+    assert_eq!(vm.gc_stats().objects, before + 1);
+}
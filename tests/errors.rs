@@ -0,0 +1,122 @@
+use lox_vm::{InterpretError, Value, VM};
+
+#[test]
+fn compile_errors_report_every_statement() {
+    let mut vm = VM::new();
+    let error = vm.interpret("print 1\nvar = 2;\nprint \"ok\";\n").unwrap_err();
+
+    let InterpretError::CompilerError(diagnostics) = &error else {
+        panic!("expected a compile error, got:\n{error}");
+    };
+    assert_eq!(diagnostics.len(), 2);
+
+    assert_eq!(diagnostics[0].message, "Expect ';' after value.");
+    assert_eq!((diagnostics[0].span.line, diagnostics[0].span.column), (2, 1));
+    assert_eq!(diagnostics[0].lexeme, "var");
+
+    assert_eq!(diagnostics[1].message, "Expect variable name.");
+    assert_eq!((diagnostics[1].span.line, diagnostics[1].span.column), (2, 5));
+    assert_eq!(diagnostics[1].lexeme, "=");
+}
+
+#[test]
+fn scanner_errors_have_their_own_code() {
+    let mut vm = VM::new();
+    let error = vm.interpret("var a = @;\nprint 1 + ;\n").unwrap_err();
+
+    let InterpretError::CompilerError(diagnostics) = &error else {
+        panic!("expected a compile error, got:\n{error}");
+    };
+    let codes: Vec<&str> = diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(codes, ["E0001", "E0002"]);
+    assert_eq!(diagnostics[0].lexeme, "@");
+}
+
+#[test]
+fn runtime_errors_carry_a_stack_trace() {
+    let mut vm = VM::new();
+    let error = vm.interpret("fun inner() {\n  return 1 + nil;\n}\nfun outer() {\n  inner();\n}\nouter();\n").unwrap_err();
+
+    let InterpretError::RuntimeError(runtime) = &error else {
+        panic!("expected a runtime error, got:\n{error}");
+    };
+    assert_eq!(runtime.diagnostic.message, "Operands must be two numbers or two strings.");
+    assert_eq!(runtime.diagnostic.span.line, 2);
+
+    let frames: Vec<String> = runtime.trace.iter().map(|frame| frame.to_string()).collect();
+    assert_eq!(frames, ["[line 2] in inner()", "[line 5] in outer()", "[line 7] in script"]);
+}
+
+#[test]
+fn super_calls_point_at_the_method_name() {
+    let mut vm = VM::new();
+    let source = "class A {}\nclass B < A {\n  m() {\n    super.missing(1,\n      2);\n  }\n}\nB().m();\n";
+    let error = vm.interpret(source).unwrap_err();
+
+    let InterpretError::RuntimeError(runtime) = &error else {
+        panic!("expected a runtime error, got:\n{error}");
+    };
+    assert_eq!(runtime.diagnostic.message, "Undefined property 'missing'.");
+    assert_eq!((runtime.diagnostic.span.line, runtime.diagnostic.span.column), (4, 11));
+    assert_eq!(runtime.diagnostic.lexeme, "missing");
+}
+
+#[test]
+fn errors_render_the_source_line() {
+    let mut vm = VM::new();
+    let error = vm.interpret("var a = 1;\nprint a + b;\n").unwrap_err();
+
+    let rendered = error.to_string();
+    assert!(rendered.contains("Undefined variable 'b'."));
+    assert!(rendered.contains("2 | print a + b;"));
+    assert!(rendered.contains("  |           ^"));
+    assert!(rendered.contains("help:"));
+}
+
+#[test]
+fn vm_recovers_after_a_runtime_error() {
+    let mut vm = VM::new();
+    assert!(vm.interpret("var x = -\"oops\";").is_err());
+    vm.interpret("var y = 2;").unwrap();
+    assert!(matches!(vm.get_global("y"), Some(Value::Number(n)) if n == 2.0));
+}
+
+#[test]
+fn native_errors_become_runtime_errors() {
+    let mut vm = VM::new();
+    vm.define_native("fail", 0, |_, _| Err("Host says no.".to_string()));
+    let error = vm.interpret("fail();").unwrap_err();
+
+    assert!(matches!(&error, InterpretError::RuntimeError(runtime) if runtime.diagnostic.message == "Host says no."));
+}
+
+#[test]
+fn escaped_closures_survive_a_runtime_error() {
+    let mut vm = VM::new();
+    let source = "var f; fun g() { var x = \"captured\"; fun h() { return x; } f = h; nil(); }";
+    vm.interpret(source).unwrap();
+    assert!(vm.interpret("g();").is_err());
+
+    vm.interpret("var a = \"L1\"; var b = \"L2\"; var got = f();").unwrap();
+    assert_eq!(vm.get_global("got"), Some(Value::String("captured".to_string())));
+}
+
+#[test]
+fn natives_can_run_nested_scripts() {
+    let mut vm = VM::new();
+    vm.define_native("nested", 1, |vm, args| {
+        let Value::String(source) = &args[0] else {
+            return Err("nested() expects a string.".to_string());
+        };
+        vm.interpret(source).map(|()| Value::Nil).map_err(|_| "Nested script failed.".to_string())
+    });
+
+    vm.interpret("var inner = \"inner \"; var log = \"\";").unwrap();
+    vm.interpret("fun f() { nested(\"log = log + inner;\"); log = log + \"after\"; } f();").unwrap();
+    assert_eq!(vm.get_global("log"), Some(Value::String("inner after".to_string())));
+
+    let error = vm.interpret("var x = 1; fun g() { var local = 2; nested(\"nil();\"); } g();").unwrap_err();
+    assert!(error.to_string().contains("Nested script failed."));
+    vm.interpret("var y = x + 1;").unwrap();
+    assert!(matches!(vm.get_global("y"), Some(Value::Number(n)) if n == 2.0));
+}
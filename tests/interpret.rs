@@ -0,0 +1,170 @@
+mod common;
+
+use common::number;
+use lox_vm::{Value, VM};
+
+fn run(source: &str) -> VM {
+    let mut vm = VM::new();
+    if let Err(error) = vm.interpret(source) {
+        panic!("unexpected error:\n{error}");
+    }
+    vm
+}
+
+fn string(vm: &VM, name: &str) -> String {
+    match vm.get_global(name) {
+        Some(Value::String(s)) => s,
+        Some(other) => panic!("{name} is {other}, not a string"),
+        None => panic!("{name} is not defined"),
+    }
+}
+
+#[test]
+fn arithmetic_and_strings() {
+    let vm = run("var a = (1 + 2) * 3 - 4 / 2; var s = \"foo\" + \"bar\";");
+    assert_eq!(number(&vm, "a"), 7.0);
+    assert_eq!(string(&vm, "s"), "foobar");
+}
+
+#[test]
+fn globals_persist_between_calls() {
+    let mut vm = run("var count = 1;");
+    vm.interpret("count = count + 1;").unwrap();
+    assert_eq!(number(&vm, "count"), 2.0);
+}
+
+#[test]
+fn control_flow() {
+    let vm = run("
+        var total = 0;
+        for (var i = 0; i < 10; i = i + 1) {
+            if (i == 5) total = total + 100; else total = total + i;
+        }
+        while (total > 130) total = total - 1;
+    ");
+    assert_eq!(number(&vm, "total"), 130.0);
+}
+
+#[test]
+fn closures_capture_variables() {
+    let vm = run("
+        fun counter() {
+            var n = 0;
+            fun inc() { n = n + 1; return n; }
+            return inc;
+        }
+        var c = counter();
+        c(); c();
+        var result = c();
+    ");
+    assert_eq!(number(&vm, "result"), 3.0);
+}
+
+#[test]
+fn classes_and_inheritance() {
+    let vm = run("
+        class Shape {
+            init(name) { this.name = name; }
+            describe() { return this.name + \" with \" + this.sides(); }
+        }
+        class Square < Shape {
+            init() { super.init(\"square\"); }
+            sides() { return \"4 sides\"; }
+        }
+        var description = Square().describe();
+    ");
+    assert_eq!(string(&vm, "description"), "square with 4 sides");
+}
+
+#[test]
+fn host_defined_natives() {
+    let mut vm = VM::new();
+    vm.define_native("double", 1, |_, args| match args[0] {
+        Value::Number(n) => Ok(Value::Number(n * 2.0)),
+        _ => Err("double() expects a number.".to_string()),
+    });
+    vm.interpret("var x = double(21);").unwrap();
+    assert_eq!(number(&vm, "x"), 42.0);
+}
+
+#[test]
+fn natives_exchange_owned_values() {
+    let mut vm = VM::new();
+    vm.define_native("shout", 1, |_, args| match &args[0] {
+        Value::String(s) => Ok(Value::String(s.to_uppercase())),
+        other => Ok(Value::String(other.to_string())),
+    });
+    vm.define_native("leak", 0, |_, _| Ok(Value::Object("<fn f>".to_string())));
+    vm.interpret("class Point {} var a = shout(\"hi\"); var b = shout(Point);").unwrap();
+    assert_eq!(string(&vm, "a"), "HI");
+    assert_eq!(string(&vm, "b"), "Point");
+    assert!(vm.interpret("leak();").is_err());
+}
+
+#[test]
+fn globals_outlive_their_objects() {
+    let mut vm = run("var s = \"held\" + \" string\"; class C {} var c = C();");
+    let held = vm.get_global("s");
+    let instance = vm.get_global("c");
+    vm.interpret("s = nil; c = nil;").unwrap();
+    vm.collect_garbage();
+    assert_eq!(held, Some(Value::String("held string".to_string())));
+    assert!(matches!(&instance, Some(Value::Object(text)) if text == "C instance"));
+}
+
+#[test]
+fn distinct_objects_never_compare_equal() {
+    let vm = run("class C {} var a = C(); var b = C();");
+    let (a, b) = (vm.get_global("a").unwrap(), vm.get_global("b").unwrap());
+    assert_eq!(a.to_string(), b.to_string());
+    assert_ne!(a, b);
+    assert_eq!(vm.get_global("C").map(|class| class.to_string()).as_deref(), Some("C"));
+}
+
+#[test]
+fn garbage_is_collected() {
+    let mut vm = run("
+        class Node { init(next) { this.next = next; } }
+        for (var i = 0; i < 1000; i = i + 1) {
+            var a = Node(nil);
+            var b = Node(a);
+            a.next = b;
+        }
+    ");
+    let before = vm.gc_stats();
+    vm.collect_garbage();
+    let after = vm.gc_stats();
+    assert_eq!(after.collections, before.collections + 1);
+    assert!(after.objects < before.objects);
+    assert!(after.bytes_allocated < before.bytes_allocated);
+}
+
+#[test]
+fn growing_tables_count_towards_the_heap() {
+    let fields: String = (0..64).map(|i| format!("target.f{i} = {i};")).collect();
+    let mut vm = run("class Bag {} var warm = Bag(); var bag = Bag(); var target = warm;");
+    vm.interpret(&fields).unwrap();
+    vm.collect_garbage();
+    let before = vm.gc_stats().bytes_allocated;
+
+    vm.interpret(&format!("target = bag; {fields}")).unwrap();
+    vm.collect_garbage();
+    assert!(vm.gc_stats().bytes_allocated >= before + 64 * 16);
+}
+
+#[test]
+fn more_than_256_constants() {
+    let table: String = (0..300).map(|i| format!("var n{i} = {i}.5;")).collect();
+    let vm = run(&format!("{table}
+        var late = 1;
+        fun make() {{ var captured = late + n299; fun get() {{ return captured; }} return get; }}
+        class Base {{ value() {{ return 10; }} }}
+        class Derived < Base {{ value() {{ this.extra = 5; return super.value() + this.extra; }} }}
+        var fromClosure = make()();
+        var fromMethod = Derived().value();
+    "));
+    assert_eq!(number(&vm, "n299"), 299.5);
+    assert_eq!(number(&vm, "late"), 1.0);
+    assert_eq!(number(&vm, "fromClosure"), 300.5);
+    assert_eq!(number(&vm, "fromMethod"), 15.0);
+}
@@ -0,0 +1,121 @@
+use std::{cell::RefCell, io::Write, rc::Rc};
+
+use lox_vm::{compile, VM};
+
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn capture() -> (VM, SharedBuffer, SharedBuffer, SharedBuffer) {
+    let (output, diagnostics, trace) = (SharedBuffer::default(), SharedBuffer::default(), SharedBuffer::default());
+    let mut vm = VM::new();
+    vm.set_output(Box::new(output.clone()));
+    vm.set_diagnostics(Box::new(diagnostics.clone()), false);
+    vm.set_trace(Box::new(trace.clone()));
+    (vm, output, diagnostics, trace)
+}
+
+#[test]
+fn print_goes_to_the_output_sink() {
+    let (mut vm, output, diagnostics, _) = capture();
+    vm.interpret("print 1 + 2; print \"a\" + \"b\"; print nil;").unwrap();
+
+    assert_eq!(output.contents(), "3\nab\nnil\n");
+    assert_eq!(diagnostics.contents(), "");
+}
+
+#[test]
+fn errors_go_to_the_diagnostics_sink() {
+    let (mut vm, output, diagnostics, _) = capture();
+    let error = vm.interpret("print \"before\";\nprint -nil;\n").unwrap_err();
+
+    assert_eq!(output.contents(), "before\n");
+    assert_eq!(diagnostics.contents(), error.to_string());
+    assert!(diagnostics.contents().contains("Operand must be a number."));
+    assert!(!diagnostics.contents().contains('\x1b'));
+}
+
+#[test]
+fn compile_errors_produce_no_output() {
+    let (mut vm, output, diagnostics, trace) = capture();
+    assert!(vm.interpret("print 1;\nprint 2 +;\n").is_err());
+
+    assert_eq!(output.contents(), "");
+    if cfg!(not(feature = "debug_log_gc")) {
+        assert_eq!(trace.contents(), "");
+    }
+    assert!(diagnostics.contents().contains("2 | print 2 +;"));
+}
+
+#[test]
+fn execution_trace_goes_to_the_trace_sink() {
+    let (mut vm, output, _, trace) = capture();
+    vm.interpret("print 42;").unwrap();
+
+    assert_eq!(output.contents(), "42\n");
+    if cfg!(feature = "debug_trace_execution") {
+        assert!(trace.contents().contains("OP_PRINT"));
+        assert!(!trace.contents().contains("42\n"));
+    } else if cfg!(not(any(feature = "debug_print_code", feature = "debug_log_gc"))) {
+        assert_eq!(trace.contents(), "");
+    }
+}
+
+#[test]
+fn gc_log_goes_to_the_trace_sink() {
+    let (mut vm, output, _, trace) = capture();
+    vm.interpret("var s = \"a\" + \"b\"; print s;").unwrap();
+    vm.collect_garbage();
+
+    assert_eq!(output.contents(), "ab\n");
+    if cfg!(feature = "debug_log_gc") {
+        assert!(trace.contents().contains("-- gc begin"));
+        assert!(trace.contents().contains("allocate"));
+
+        // Marking only happens inside a collection, and every line carries a size.
+        let mut collecting = false;
+        for line in trace.contents().lines() {
+            match line {
+                "-- gc begin" => collecting = true,
+                "-- gc end" => collecting = false,
+                _ if line.contains(" mark ") || line.contains(" blacken ") => {
+                    assert!(collecting, "{line} logged outside a collection");
+                    assert!(line.split(' ').nth(2).is_some_and(|size| size.parse::<usize>().is_ok()), "{line} has no size");
+                }
+                _ => (),
+            }
+        }
+    } else {
+        assert!(!trace.contents().contains("-- gc"));
+    }
+}
+
+#[test]
+fn compiled_code_is_printed_when_first_loaded() {
+    let script = compile("print 1;").unwrap();
+    let (mut vm, output, _, trace) = capture();
+    vm.run_compiled(&script).unwrap();
+    let first = trace.contents();
+    vm.run_compiled(&script).unwrap();
+    let second = trace.contents()[first.len()..].to_string();
+
+    assert_eq!(output.contents(), "1\n1\n");
+    assert_eq!(first.contains("== <script> =="), cfg!(feature = "debug_print_code"));
+    assert!(!second.contains("== <script> =="));
+}